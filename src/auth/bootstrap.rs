@@ -0,0 +1,77 @@
+use crate::graphql::roles::Role;
+use crate::models::user::UserEntity;
+use crate::password::hash_password;
+use crate::password::policy::check_password;
+use crate::ModelFor;
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::UpdateOptions;
+use std::env::var;
+
+// Marker of the bootstrapped Root. It outlives the user, so a deleted or demoted Root is not recreated from stale variables.
+const ROOT_MARKER: &str = "root";
+
+// Create the first Root from ROOT_NAME, ROOT_EMAIL and ROOT_PASSWORD. Does nothing once a Root was bootstrapped,
+// so the variables can stay configured. Existing accounts are never promoted, whoever registered them first would become Root.
+pub async fn bootstrap_root(users: &ModelFor<UserEntity>, markers: &ModelFor<Document>) -> anyhow::Result<()> {
+    let (name, email, password) = match (var("ROOT_NAME"), var("ROOT_EMAIL"), var("ROOT_PASSWORD")) {
+        (Ok(name), Ok(email), Ok(password)) => (name, email, password),
+        _ => return Ok(()),
+    };
+
+    if markers.find_one(doc! { "_id": ROOT_MARKER }, None).await?.is_some() {
+        log::info!("Root has been bootstrapped, ROOT_PASSWORD is ignored and can be removed");
+        return Ok(());
+    }
+    // Installations from before the marker
+    if let Some(root) = users.find_one(doc! { "roles": Role::Root.as_str() }, None).await? {
+        claim_marker(markers, &root.id.unwrap()).await?;
+        log::info!("A Root user exists, ROOT_PASSWORD is ignored and can be removed");
+        return Ok(());
+    }
+    if users
+        .find_one(doc! { "$or": [{ "name": name.as_str() }, { "email_address": email.as_str() }] }, None)
+        .await?
+        .is_some()
+    {
+        log::warn!("Cannot bootstrap Root, the name '{}' or email address '{}' is taken by another user", name, email);
+        return Ok(());
+    }
+    check_password(password.as_str(), &[name.as_str(), email.as_str()])
+        .map_err(|err| anyhow::anyhow!("ROOT_PASSWORD does not satisfy the password policy: {}", err))?;
+
+    let password_hash = hash_password(password).await?;
+    let mut root = UserEntity::new(name, email, password_hash);
+    root.roles = vec![Role::Root, Role::Admin, Role::User];
+    // The address is provided by the operator
+    root.email_verified = true;
+
+    // Of several instances starting at once only the one that inserts the marker creates the user
+    if !claim_marker(markers, &root.id.unwrap()).await? {
+        return Ok(());
+    }
+    if let Err(err) = users.insert_one(&root, None).await {
+        // Try again on the next start
+        let _ = markers.delete_one(doc! { "_id": ROOT_MARKER }, None).await;
+        return Err(err.into());
+    }
+
+    log::info!("Created Root user '{}'", root.name);
+    Ok(())
+}
+
+// Whether the marker was inserted by this call
+async fn claim_marker(markers: &ModelFor<Document>, user: &ObjectId) -> mongodb::error::Result<bool> {
+    let options = UpdateOptions::builder().upsert(true).build();
+    let now = DateTime::from_millis(Utc::now().timestamp_millis());
+    let update = doc! { "$setOnInsert": { "user": user, "when_created": now } };
+    match markers.update_one(doc! { "_id": ROOT_MARKER }, update, options).await {
+        Ok(result) => Ok(result.upserted_id.is_some()),
+        // Concurrent upserts on the same id fail with a duplicate key for all but one
+        Err(err) => match markers.find_one(doc! { "_id": ROOT_MARKER }, None).await? {
+            Some(_) => Ok(false),
+            None => Err(err),
+        },
+    }
+}
@@ -0,0 +1,153 @@
+use crate::auth::hash::hash_token;
+use crate::auth::signed::{reject_signed_tokens_before_now, TokenMode, AUTH_TOKEN_MODE};
+use crate::auth::CurrentSession;
+use crate::connections::PubSub;
+use crate::models::user::UserEntity;
+use crate::Scope;
+use chrono::Utc;
+use fred::interfaces::{KeysInterface, SetsInterface};
+use fred::types::{Expiration, SetOptions};
+use lazy_static::lazy_static;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use std::env::var;
+
+lazy_static! {
+    // Seconds a resolved token is kept in redis
+    static ref USER_CACHE_TTL: i64 = var("USER_CACHE_TTL")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(60);
+    // Seconds between two `last_access` writes of a session
+    static ref LAST_ACCESS_INTERVAL: i64 = var("LAST_ACCESS_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(60);
+}
+
+// Counter shared by all instances to order cache fills and invalidations
+const SEQUENCE_KEY: &str = "auth_seq";
+
+#[derive(Serialize, Deserialize)]
+struct CachedSession {
+    user: UserEntity,
+    session: String,
+    scopes: Option<Vec<Scope>>,
+    vaults: Option<Vec<String>>,
+    // Sequence taken before the user was read from the database
+    #[serde(default)]
+    sequence: i64,
+}
+
+// Keyed by the token hash, the plain token never reaches redis
+fn cache_key(token: &str) -> String {
+    format!("auth:{}", hash_token(token))
+}
+
+// Set of the cache keys of a user, used to invalidate them
+fn user_key(user: &ObjectId) -> String {
+    format!("auth_user:{}", user.to_hex())
+}
+
+// Sequence of the last invalidation of a user, entries read before it are stale
+fn invalidated_key(user: &ObjectId) -> String {
+    format!("auth_invalidated:{}", user.to_hex())
+}
+
+// Take a sequence before reading a user, required to cache the result
+pub async fn cache_sequence(pubsub: &PubSub) -> Option<i64> {
+    pubsub.publish.incr::<i64, _>(SEQUENCE_KEY).await.ok()
+}
+
+pub async fn cached_session(pubsub: &PubSub, token: &str) -> Option<(UserEntity, CurrentSession)> {
+    let bytes = pubsub.publish.get::<Option<Vec<u8>>, _>(cache_key(token)).await.ok()??;
+    let cached = mongodb::bson::from_slice::<CachedSession>(&bytes).ok()?;
+    // An invalidation may have raced with the lookup that filled the cache
+    let invalidated = pubsub
+        .publish
+        .get::<Option<i64>, _>(invalidated_key(&cached.user.id?))
+        .await
+        .ok()?
+        .unwrap_or(0);
+    if cached.sequence < invalidated {
+        return None;
+    }
+    let session = CurrentSession {
+        id: cached.session,
+        user: cached.user.id?,
+        scopes: cached.scopes,
+        vaults: cached.vaults,
+    };
+    Some((cached.user, session))
+}
+
+// Remember a resolved token, never beyond its expiry
+pub async fn cache_session(pubsub: &PubSub, token: &str, user: &UserEntity, session: &CurrentSession, expire: Option<DateTime>, sequence: i64) {
+    let mut ttl = *USER_CACHE_TTL;
+    if let Some(expire) = expire {
+        ttl = ttl.min((expire.timestamp_millis() - Utc::now().timestamp_millis()) / 1000);
+    }
+    if ttl <= 0 {
+        return;
+    }
+
+    // Token hashes and sessions are not needed to handle a request
+    let mut user = user.clone();
+    user.access_token.clear();
+    user.refresh_token.clear();
+    user.sessions.clear();
+    user.api_token.clear();
+    user.password_reset = None;
+    user.totp = None;
+    let cached = CachedSession {
+        user,
+        session: session.id.clone(),
+        scopes: session.scopes.clone(),
+        vaults: session.vaults.clone(),
+        sequence,
+    };
+    let bytes = match mongodb::bson::to_vec(&cached) {
+        Ok(bytes) => bytes,
+        Err(_) => return,
+    };
+
+    let key = cache_key(token);
+    let index = user_key(&session.user);
+    let _ = pubsub.publish.set::<(), _, _>(key.as_str(), bytes, Some(Expiration::EX(ttl)), None, false).await;
+    let _ = pubsub.publish.sadd::<(), _, _>(index.as_str(), key).await;
+    let _ = pubsub.publish.expire::<(), _>(index, *USER_CACHE_TTL).await;
+}
+
+// Drop every cached token of a user, required whenever roles, credentials or sessions change
+pub async fn invalidate_user(pubsub: &PubSub, user: &ObjectId) {
+    // Signed tokens are not cached but carry the roles themselves
+    if *AUTH_TOKEN_MODE == TokenMode::Signed {
+        reject_signed_tokens_before_now(pubsub, user).await;
+    }
+    // Outdates entries of lookups that are still running, kept as it is one small key per user
+    if let Some(sequence) = cache_sequence(pubsub).await {
+        let _ = pubsub.publish.set::<(), _, _>(invalidated_key(user), sequence, None, None, false).await;
+    }
+    let index = user_key(user);
+    if let Ok(keys) = pubsub.publish.smembers::<Vec<String>, _>(index.as_str()).await {
+        if !keys.is_empty() {
+            let _ = pubsub.publish.del::<(), _>(keys).await;
+        }
+    }
+    let _ = pubsub.publish.del::<(), _>(index).await;
+}
+
+// Allows an action once per interval across all instances. Without redis it is always allowed.
+pub async fn acquire(pubsub: &PubSub, key: String, seconds: i64) -> bool {
+    let result = pubsub
+        .publish
+        .set::<Option<String>, _, _>(key, 1, Some(Expiration::EX(seconds)), Some(SetOptions::NX), false)
+        .await;
+    !matches!(result, Ok(None))
+}
+
+// Whether `last_access` of a session is due to be written again
+pub async fn should_touch(pubsub: &PubSub, session: &str) -> bool {
+    acquire(pubsub, format!("touched:{}", session), *LAST_ACCESS_INTERVAL).await
+}
@@ -0,0 +1,78 @@
+use crate::auth::URL_SAFE_ENGINE;
+use crate::models::user::UserEntity;
+use crate::ModelFor;
+use dryoc::classic::crypto_generichash::crypto_generichash;
+use futures::stream::TryStreamExt;
+use lazy_static::lazy_static;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Document};
+use mongodb::options::UpdateOptions;
+use std::env::var;
+
+lazy_static! {
+    // Secret mixed into token hashes so a leaked database cannot be brute-forced offline,
+    // changing it invalidates every token and recovery code
+    static ref TOKEN_HASH_KEY: Vec<u8> = {
+        let key = var("TOKEN_HASH_KEY").expect("TOKEN_HASH_KEY not set in environment").into_bytes();
+        if key.len() < 16 || key.len() > 64 {
+            panic!("TOKEN_HASH_KEY must be between 16 and 64 bytes long");
+        }
+        key
+    };
+}
+
+// Fail on startup rather than on the first token to hash
+pub fn ensure_hash_key() {
+    lazy_static::initialize(&TOKEN_HASH_KEY);
+}
+
+// Tokens are only stored as BLAKE2b hash, the plain token is handed out once
+pub fn hash_token(token: &str) -> String {
+    let mut hash = [0u8; 32];
+    crypto_generichash(&mut hash, token.as_bytes(), Some(TOKEN_HASH_KEY.as_slice())).expect("Cannot hash token");
+    base64::encode_engine(hash, &URL_SAFE_ENGINE)
+}
+
+// Matches a token entry by its hash, or by the plain token for entries that were not migrated yet
+pub fn token_filter(token: &str) -> Document {
+    doc! { "$or": [
+        { "token": hash_token(token), "hashed": true },
+        { "token": token, "hashed": { "$ne": true } },
+    ]}
+}
+
+// Replace a plain token of a user with its hash. `field` is either `access_token` or `refresh_token`.
+pub async fn hash_stored_token(users: &ModelFor<UserEntity>, user: &ObjectId, field: &str, token: &str) -> mongodb::error::Result<()> {
+    let options = UpdateOptions::builder()
+        .array_filters(vec![doc! { "t.token": token, "t.hashed": { "$ne": true } }])
+        .build();
+    let mut update = Document::new();
+    update.insert(format!("{}.$[t].token", field), hash_token(token));
+    update.insert(format!("{}.$[t].hashed", field), true);
+    users
+        .update_one(doc! { "_id": user }, doc! { "$set": update }, options)
+        .await?;
+    Ok(())
+}
+
+// Hash every token still stored in plain text
+pub async fn migrate_plain_tokens(users: &ModelFor<UserEntity>) -> mongodb::error::Result<u64> {
+    let filter = doc! { "$or": [
+        { "access_token": { "$elemMatch": { "hashed": { "$ne": true } } } },
+        { "refresh_token": { "$elemMatch": { "hashed": { "$ne": true } } } },
+    ]};
+    let mut cursor = users.find(filter, None).await?;
+    let mut migrated = 0;
+    while let Some(user) = cursor.try_next().await? {
+        let id = user.id.unwrap();
+        for token in user.access_token.iter().filter(|t| !t.hashed) {
+            hash_stored_token(users, &id, "access_token", token.token.as_str()).await?;
+            migrated += 1;
+        }
+        for token in user.refresh_token.iter().filter(|t| !t.hashed) {
+            hash_stored_token(users, &id, "refresh_token", token.token.as_str()).await?;
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
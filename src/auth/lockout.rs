@@ -0,0 +1,184 @@
+use crate::connections::PubSub;
+use fred::interfaces::{KeysInterface, SetsInterface};
+use fred::types::{Expiration, SetOptions};
+use lazy_static::lazy_static;
+use std::env::var;
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    var(name).ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(default)
+}
+
+lazy_static! {
+    // Failed logins before an account is locked
+    static ref LOGIN_FREE_ATTEMPTS: i64 = env_i64("LOGIN_FREE_ATTEMPTS", 5);
+    // Failed logins before an address is locked, higher since addresses may be shared
+    static ref LOGIN_IP_FREE_ATTEMPTS: i64 = env_i64("LOGIN_IP_FREE_ATTEMPTS", 20);
+    // Seconds of the first lockout, doubled with every further failure
+    static ref LOGIN_LOCKOUT_BASE: i64 = env_i64("LOGIN_LOCKOUT_BASE", 30);
+    static ref LOGIN_LOCKOUT_MAX: i64 = env_i64("LOGIN_LOCKOUT_MAX", 3600);
+    // Seconds after which failed logins are forgotten
+    static ref LOGIN_FAILURE_WINDOW: i64 = env_i64("LOGIN_FAILURE_WINDOW", 86400);
+}
+
+// Set of accounts with a lockout, entries are pruned when listed
+const LOCKED_ACCOUNTS_KEY: &str = "locked_accounts";
+
+// What failed logins are counted for. Accounts are the user id, or the name that was tried for unknown users.
+pub enum LoginSubject<'a> {
+    Account(&'a str),
+    Ip(&'a str),
+}
+
+impl LoginSubject<'_> {
+    fn failures_key(&self) -> String {
+        match self {
+            LoginSubject::Account(account) => format!("login_failures:account:{}", account),
+            LoginSubject::Ip(ip) => format!("login_failures:ip:{}", ip),
+        }
+    }
+
+    fn lock_key(&self) -> String {
+        match self {
+            LoginSubject::Account(account) => format!("login_lock:account:{}", account),
+            LoginSubject::Ip(ip) => format!("login_lock:ip:{}", ip),
+        }
+    }
+
+    fn free_attempts(&self) -> i64 {
+        match self {
+            LoginSubject::Account(_) => *LOGIN_FREE_ATTEMPTS,
+            LoginSubject::Ip(_) => *LOGIN_IP_FREE_ATTEMPTS,
+        }
+    }
+}
+
+fn lockout_seconds(failures: i64, free_attempts: i64) -> Option<i64> {
+    if failures <= free_attempts {
+        return None;
+    }
+    let doublings = (failures - free_attempts - 1).min(32) as u32;
+    Some(LOGIN_LOCKOUT_BASE.saturating_mul(2i64.saturating_pow(doublings)).min(*LOGIN_LOCKOUT_MAX))
+}
+
+// Seconds until the subject may try again, if it is locked
+pub async fn locked_for(pubsub: &PubSub, subject: &LoginSubject<'_>) -> Option<i64> {
+    match pubsub.publish.ttl::<i64, _>(subject.lock_key()).await {
+        Ok(seconds) if seconds > 0 => Some(seconds),
+        _ => None,
+    }
+}
+
+// An attempt that has been counted as failure before the credentials were checked
+pub struct Attempt {
+    failures_key: String,
+    // Set if the free attempts were used up and this attempt holds the lock
+    lock_key: Option<String>,
+}
+
+// Count an attempt up front, so concurrent guesses cannot slip past the limit. Once the free attempts
+// are used up, only the attempt that takes the lock is let through. Returns the seconds to wait otherwise.
+pub async fn reserve_attempt(pubsub: &PubSub, subject: &LoginSubject<'_>) -> Result<Attempt, i64> {
+    if let Some(retry_after) = locked_for(pubsub, subject).await {
+        return Err(retry_after);
+    }
+    let failures_key = subject.failures_key();
+    let failures = match pubsub.publish.incr::<i64, _>(failures_key.as_str()).await {
+        Ok(failures) => failures,
+        Err(_) => return Ok(Attempt { failures_key, lock_key: None }),
+    };
+    let _ = pubsub.publish.expire::<(), _>(failures_key.as_str(), *LOGIN_FAILURE_WINDOW).await;
+
+    let seconds = match lockout_seconds(failures, subject.free_attempts()) {
+        Some(seconds) => seconds,
+        None => return Ok(Attempt { failures_key, lock_key: None }),
+    };
+    let lock_key = subject.lock_key();
+    let locked = pubsub
+        .publish
+        .set::<Option<String>, _, _>(lock_key.as_str(), failures, Some(Expiration::EX(seconds)), Some(SetOptions::NX), false)
+        .await;
+    if matches!(locked, Ok(None)) {
+        // Another attempt holds the lock, this one is not counted
+        let _ = pubsub.publish.decr::<(), _>(failures_key).await;
+        return Err(locked_for(pubsub, subject).await.unwrap_or(seconds));
+    }
+    if let LoginSubject::Account(account) = subject {
+        let _ = pubsub.publish.sadd::<(), _, _>(LOCKED_ACCOUNTS_KEY, *account).await;
+    }
+    Ok(Attempt {
+        failures_key,
+        lock_key: Some(lock_key),
+    })
+}
+
+// Take back an attempt that did not fail, along with the lock it holds
+pub async fn release_attempt(pubsub: &PubSub, attempt: Attempt) {
+    let _ = pubsub.publish.decr::<(), _>(attempt.failures_key).await;
+    if let Some(lock_key) = attempt.lock_key {
+        let _ = pubsub.publish.del::<(), _>(lock_key).await;
+    }
+}
+
+// Forget failed logins of an account, after a successful login or when an admin unlocks it
+pub async fn clear_failures(pubsub: &PubSub, account: &str) {
+    let subject = LoginSubject::Account(account);
+    let _ = pubsub.publish.del::<(), _>(vec![subject.failures_key(), subject.lock_key()]).await;
+    let _ = pubsub.publish.srem::<(), _, _>(LOCKED_ACCOUNTS_KEY, account).await;
+}
+
+pub struct Lockout {
+    pub account: String,
+    pub failures: i64,
+    pub retry_after: i64,
+}
+
+// Accounts that are currently locked
+pub async fn locked_accounts(pubsub: &PubSub) -> anyhow::Result<Vec<Lockout>> {
+    let accounts = pubsub.publish.smembers::<Vec<String>, _>(LOCKED_ACCOUNTS_KEY).await?;
+    let mut lockouts = vec![];
+    for account in accounts {
+        let subject = LoginSubject::Account(account.as_str());
+        match locked_for(pubsub, &subject).await {
+            Some(retry_after) => {
+                let failures = pubsub
+                    .publish
+                    .get::<Option<i64>, _>(subject.failures_key())
+                    .await?
+                    .unwrap_or_default();
+                lockouts.push(Lockout {
+                    account,
+                    failures,
+                    retry_after,
+                });
+            }
+            None => {
+                let _ = pubsub.publish.srem::<(), _, _>(LOCKED_ACCOUNTS_KEY, account.as_str()).await;
+            }
+        }
+    }
+    Ok(lockouts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_attempts_are_not_locked() {
+        assert_eq!(lockout_seconds(0, 5), None);
+        assert_eq!(lockout_seconds(5, 5), None);
+    }
+
+    #[test]
+    fn lockout_doubles_with_every_failure() {
+        assert_eq!(lockout_seconds(6, 5), Some(30));
+        assert_eq!(lockout_seconds(7, 5), Some(60));
+        assert_eq!(lockout_seconds(8, 5), Some(120));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        assert_eq!(lockout_seconds(20, 5), Some(3600));
+        assert_eq!(lockout_seconds(i64::MAX, 5), Some(3600));
+    }
+}
@@ -0,0 +1,453 @@
+use crate::auth::cache::{cache_sequence, cache_session, cached_session, invalidate_user, should_touch};
+use crate::auth::hash::{hash_stored_token, hash_token, token_filter};
+use crate::auth::signed::{is_issued_too_early, is_session_revoked, is_signed_token, revoke_signed_session, sign_token, verify_token, TokenClaims, TokenMode, AUTH_TOKEN_MODE};
+use crate::connections::PubSub;
+use crate::models::user::{AccessTokenEntity, ApiTokenEntity, RefreshTokenEntity, SessionEntity, UserEntity};
+use crate::{ModelFor, Scope};
+use actix_web::HttpRequest;
+use base64::alphabet::URL_SAFE;
+use base64::engine::fast_portable::{FastPortable, NO_PAD};
+use chrono::{Duration, Utc};
+use fred::interfaces::PubsubInterface;
+use futures::stream::TryStreamExt;
+use lazy_static::lazy_static;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, to_bson, DateTime};
+use mongodb::options::{FindOneOptions, UpdateOptions};
+use std::env::var;
+use std::fmt;
+use uuid::Uuid;
+
+pub mod bootstrap;
+pub mod cache;
+pub mod hash;
+pub mod lockout;
+pub mod oidc;
+pub mod reset;
+pub mod signed;
+pub mod totp;
+pub mod verification;
+
+lazy_static! {
+    // Lifetime of access tokens in minutes
+    pub static ref ACCESS_TOKEN_TTL: i64 = var("ACCESS_TOKEN_TTL")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(15);
+    // Lifetime of refresh tokens in days
+    pub static ref REFRESH_TOKEN_TTL: i64 = var("REFRESH_TOKEN_TTL")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(30);
+}
+
+pub(crate) const URL_SAFE_ENGINE: FastPortable = FastPortable::from(&URL_SAFE, NO_PAD);
+
+// Redis topic carrying the ids of revoked sessions
+pub const SESSION_REVOKED_TOPIC: &str = "session_revoked";
+
+// The session or API token a request has been authenticated with
+#[derive(Debug, Clone)]
+pub struct CurrentSession {
+    pub id: String,
+    pub user: ObjectId,
+    // Only set for API tokens, sessions of a login may do everything their user may do
+    pub scopes: Option<Vec<Scope>>,
+    pub vaults: Option<Vec<String>>,
+}
+
+impl CurrentSession {
+    pub fn is_api_token(&self) -> bool {
+        self.scopes.is_some()
+    }
+}
+
+// Client details recorded when a session is created
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl RequestInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        Self {
+            user_agent: req
+                .headers()
+                .get(actix_web::http::header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned()),
+            ip: req.connection_info().realip_remote_addr().map(|ip| ip.to_owned()),
+        }
+    }
+}
+
+// Freshly issued tokens, the only time the plain tokens are handed out
+pub struct IssuedTokens {
+    pub family: String,
+    pub access_token: String,
+    pub access_expire: DateTime,
+    pub refresh_token: String,
+    pub refresh_expire: DateTime,
+}
+
+#[derive(Debug)]
+pub enum RefreshError {
+    Invalid,
+    Expired,
+    Reused,
+    Database,
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshError::Invalid => write!(f, "The refresh token is invalid"),
+            RefreshError::Expired => write!(f, "The refresh token has expired"),
+            RefreshError::Reused => write!(f, "The refresh token has already been used, the session has been revoked"),
+            RefreshError::Database => write!(f, "Cannot write to database"),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for RefreshError {
+    fn from(_: mongodb::error::Error) -> Self {
+        RefreshError::Database
+    }
+}
+
+// 256 bit of randomness, url safe encoded
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    dryoc::rng::copy_randombytes(&mut bytes);
+    base64::encode_engine(bytes, &URL_SAFE_ENGINE)
+}
+
+fn expire_in(duration: Duration) -> DateTime {
+    DateTime::from_millis((Utc::now() + duration).timestamp_millis())
+}
+
+// Issue a short lived access token together with a refresh token for a token family
+pub async fn issue_tokens(
+    users: &ModelFor<UserEntity>,
+    user: &UserEntity,
+    family: &str,
+    access_minutes: i64,
+) -> anyhow::Result<IssuedTokens> {
+    let access_expire = expire_in(Duration::minutes(access_minutes));
+    let refresh_token = generate_token();
+    let refresh = RefreshTokenEntity::new(hash_token(&refresh_token), expire_in(Duration::days(*REFRESH_TOKEN_TTL)), family.to_owned());
+
+    // Signed access tokens are not stored, only their refresh token is
+    let mut push = doc! { "refresh_token": to_bson(&refresh)? };
+    let access_token = match *AUTH_TOKEN_MODE {
+        TokenMode::Signed => sign_token(&TokenClaims::new(user, family, access_expire)),
+        TokenMode::Opaque => {
+            let token = generate_token();
+            let access = AccessTokenEntity::new(hash_token(&token), access_expire, family.to_owned());
+            push.insert("access_token", to_bson(&access)?);
+            token
+        }
+    };
+    let id = user.id.unwrap();
+
+    // Drop expired tokens before adding new ones
+    let now = DateTime::from_millis(Utc::now().timestamp_millis());
+    users
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$pull": {
+                "access_token": { "expire": { "$lt": now } },
+                "refresh_token": { "expire": { "$lt": now } },
+            }},
+            None,
+        )
+        .await?;
+
+    let result = users
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$push": push },
+            None,
+        )
+        .await?;
+    if result.modified_count != 1 {
+        anyhow::bail!("Token could not be stored");
+    }
+
+    Ok(IssuedTokens {
+        family: family.to_owned(),
+        access_token,
+        access_expire,
+        refresh_token,
+        refresh_expire: refresh.expire,
+    })
+}
+
+// Remove the session and every access and refresh token of its family. Open subscriptions of the
+// session are closed through the revocation topic. Returns false if there was no such session.
+pub async fn revoke_family(users: &ModelFor<UserEntity>, pubsub: &PubSub, user: &ObjectId, family: &str) -> mongodb::error::Result<bool> {
+    let result = users
+        .update_one(
+            doc! { "_id": user },
+            doc! { "$pull": {
+                "access_token": { "family": family },
+                "refresh_token": { "family": family },
+                "sessions": { "id": family },
+                "api_token": { "id": family },
+            }},
+            None,
+        )
+        .await?;
+    if result.modified_count == 0 {
+        return Ok(false);
+    }
+    invalidate_user(pubsub, user).await;
+    publish_session_revoked(pubsub, family).await;
+    Ok(true)
+}
+
+// Reject signed tokens of a session and close its open subscriptions
+pub async fn publish_session_revoked(pubsub: &PubSub, session: &str) {
+    if *AUTH_TOKEN_MODE == TokenMode::Signed {
+        revoke_signed_session(pubsub, session).await;
+    }
+    let _ = pubsub
+        .publish
+        .publish::<String, _, String>(SESSION_REVOKED_TOPIC, session.to_owned())
+        .await;
+}
+
+// Access tokens of older versions were issued without a session. Give each of them its own
+// family so they stay valid until they expire and can be listed and revoked like any session.
+pub async fn migrate_legacy_sessions(users: &ModelFor<UserEntity>) -> mongodb::error::Result<u64> {
+    let mut cursor = users
+        .find(doc! { "access_token": { "$elemMatch": { "family": null } } }, None)
+        .await?;
+    let mut migrated = 0;
+    while let Some(user) = cursor.try_next().await? {
+        for token in user.access_token.iter().filter(|t| t.family.is_none()) {
+            let session = SessionEntity::new(Uuid::new_v4().to_string(), None, None);
+            let options = UpdateOptions::builder()
+                .array_filters(vec![doc! { "t.token": token.token.as_str(), "t.family": null }])
+                .build();
+            users
+                .update_one(
+                    doc! { "_id": user.id.unwrap() },
+                    doc! {
+                        "$set": { "access_token.$[t].family": session.id.as_str() },
+                        "$push": { "sessions": to_bson(&session)? },
+                    },
+                    options,
+                )
+                .await?;
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
+
+// Start a new session and issue its first tokens
+pub async fn create_session(
+    users: &ModelFor<UserEntity>,
+    user: &UserEntity,
+    info: &RequestInfo,
+    access_minutes: i64,
+) -> anyhow::Result<IssuedTokens> {
+    let session = SessionEntity::new(Uuid::new_v4().to_string(), info.user_agent.clone(), info.ip.clone());
+    users
+        .update_one(
+            doc! { "_id": user.id.unwrap() },
+            doc! { "$push": { "sessions": to_bson(&session)? }},
+            None,
+        )
+        .await?;
+    issue_tokens(users, user, session.id.as_str(), access_minutes).await
+}
+
+// Resolve a user and its session from an access token
+pub async fn authenticate(users: &ModelFor<UserEntity>, pubsub: &PubSub, token: &str) -> Option<(UserEntity, CurrentSession)> {
+    // Signed tokens describe the user themselves, only early revocation has to be checked
+    if is_signed_token(token) {
+        let claims = verify_token(token)?;
+        if is_session_revoked(pubsub, claims.sid.as_str()).await || is_issued_too_early(pubsub, &claims).await {
+            return None;
+        }
+        return claims.into_session();
+    }
+
+    if let Some(cached) = cached_session(pubsub, token).await {
+        return Some(cached);
+    }
+    // Taken before the lookup, so an invalidation during the lookup outdates the entry
+    let sequence = cache_sequence(pubsub).await;
+    let (user, session, expire) = match authenticate_access_token(users, token).await {
+        Some(found) => found,
+        None => authenticate_api_token(users, token).await?,
+    };
+    if let Some(sequence) = sequence {
+        cache_session(pubsub, token, &user, &session, expire, sequence).await;
+    }
+    Some((user, session))
+}
+
+async fn authenticate_access_token(users: &ModelFor<UserEntity>, token: &str) -> Option<(UserEntity, CurrentSession, Option<DateTime>)> {
+    let options = FindOneOptions::builder()
+        .projection(doc! { "password_hash": 0 })
+        .build();
+    // Token and expiry have to match on the same entry, legacy tokens got a session at startup
+    let now = DateTime::from_millis(Utc::now().timestamp_millis());
+    let mut matcher = token_filter(token);
+    matcher.insert("expire", doc! { "$gte": now });
+    matcher.insert("family", doc! { "$ne": null });
+    let user = users.find_one(doc! { "access_token": { "$elemMatch": matcher } }, options).await.ok()??;
+
+    let hash = hash_token(token);
+    let entry = user
+        .access_token
+        .iter()
+        .find(|t| if t.hashed { t.token == hash } else { t.token == token })?;
+    if !entry.hashed {
+        let _ = hash_stored_token(users, &user.id.unwrap(), "access_token", token).await;
+    }
+    let family = entry.family.clone()?;
+
+    let expire = entry.expire;
+
+    let session = CurrentSession {
+        id: family,
+        user: user.id.unwrap(),
+        scopes: None,
+        vaults: None,
+    };
+    Some((user, session, Some(expire)))
+}
+
+async fn authenticate_api_token(users: &ModelFor<UserEntity>, token: &str) -> Option<(UserEntity, CurrentSession, Option<DateTime>)> {
+    let options = FindOneOptions::builder()
+        .projection(doc! { "password_hash": 0 })
+        .build();
+    let hash = hash_token(token);
+    let now = DateTime::from_millis(Utc::now().timestamp_millis());
+    let filter = doc! { "api_token": { "$elemMatch": {
+        "token": hash.as_str(),
+        "$or": [{ "expire": null }, { "expire": { "$gte": now } }],
+    }}};
+    let user = users.find_one(filter, options).await.ok()??;
+    let entry = user.api_token.iter().find(|t| t.token == hash)?;
+
+    let session = CurrentSession {
+        id: entry.id.clone(),
+        user: user.id.unwrap(),
+        scopes: Some(entry.scopes.clone()),
+        vaults: entry.vaults.clone(),
+    };
+    let expire = entry.expire;
+    Some((user, session, expire))
+}
+
+// Create a personal API token, the plain token is only returned here
+pub async fn create_api_token(
+    users: &ModelFor<UserEntity>,
+    user: &ObjectId,
+    name: String,
+    scopes: Vec<Scope>,
+    vaults: Option<Vec<String>>,
+    expire_days: Option<i64>,
+) -> anyhow::Result<(String, ApiTokenEntity)> {
+    let token = generate_token();
+    let entity = ApiTokenEntity::new(name, hash_token(&token), scopes, vaults, expire_days.map(|days| expire_in(Duration::days(days))));
+    let result = users
+        .update_one(doc! { "_id": user }, doc! { "$push": { "api_token": to_bson(&entity)? }}, None)
+        .await?;
+    if result.modified_count != 1 {
+        anyhow::bail!("Token could not be stored");
+    }
+    Ok((token, entity))
+}
+
+// Update the access timestamps of the user and its session
+async fn touch_session(users: &ModelFor<UserEntity>, session: &CurrentSession) -> mongodb::error::Result<()> {
+    let now = DateTime::from_millis(Utc::now().timestamp_millis());
+    let options = UpdateOptions::builder()
+        .array_filters(vec![doc! { "s.id": session.id.as_str() }])
+        .build();
+    let mut update = doc! { "last_access": now };
+    match session.is_api_token() {
+        true => update.insert("api_token.$[s].last_used", now),
+        false => update.insert("sessions.$[s].last_used", now),
+    };
+    users
+        .update_one(doc! { "_id": session.user }, doc! { "$set": update }, options)
+        .await?;
+    Ok(())
+}
+
+// Record an access without holding up the request, at most once per interval and session
+pub async fn record_access(users: &ModelFor<UserEntity>, pubsub: &PubSub, session: &CurrentSession) {
+    if !should_touch(pubsub, session.id.as_str()).await {
+        return;
+    }
+    let users = users.clone();
+    let session = session.clone();
+    tokio::spawn(async move {
+        if let Err(err) = touch_session(&users, &session).await {
+            log::warn!("Cannot update session timestamps: {}", err);
+        }
+    });
+}
+
+// Check whether a session has not been revoked in the meantime
+pub async fn is_session_active(users: &ModelFor<UserEntity>, session: &CurrentSession) -> bool {
+    matches!(
+        users
+            .count_documents(doc! { "_id": session.user, "$or": [{ "sessions.id": session.id.as_str() }, { "api_token.id": session.id.as_str() }] }, None)
+            .await,
+        Ok(count) if count > 0
+    )
+}
+
+// Exchange a refresh token for a new token pair of the same family. Refresh tokens rotate,
+// presenting one a second time means it leaked and revokes the whole family.
+pub async fn refresh_tokens(users: &ModelFor<UserEntity>, pubsub: &PubSub, refresh_token: &str) -> Result<IssuedTokens, RefreshError> {
+    let user = users
+        .find_one(doc! { "refresh_token": { "$elemMatch": token_filter(refresh_token) } }, None)
+        .await?
+        .ok_or(RefreshError::Invalid)?;
+    let user_id = user.id.unwrap();
+    let hash = hash_token(refresh_token);
+    let entry = user
+        .refresh_token
+        .iter()
+        .find(|t| if t.hashed { t.token == hash } else { t.token == refresh_token })
+        .cloned()
+        .ok_or(RefreshError::Invalid)?;
+    if !entry.hashed {
+        hash_stored_token(users, &user_id, "refresh_token", refresh_token).await?;
+    }
+
+    if entry.used {
+        revoke_family(users, pubsub, &user_id, entry.family.as_str()).await?;
+        return Err(RefreshError::Reused);
+    }
+    if entry.expire.timestamp_millis() < Utc::now().timestamp_millis() {
+        return Err(RefreshError::Expired);
+    }
+
+    // Claim the token atomically so concurrent exchanges are detected as reuse as well
+    let claimed = users
+        .update_one(
+            doc! { "_id": user_id, "refresh_token": { "$elemMatch": { "token": hash.as_str(), "hashed": true, "used": false } } },
+            doc! { "$set": { "refresh_token.$.used": true } },
+            None,
+        )
+        .await?;
+    if claimed.modified_count == 0 {
+        revoke_family(users, pubsub, &user_id, entry.family.as_str()).await?;
+        return Err(RefreshError::Reused);
+    }
+
+    issue_tokens(users, &user, entry.family.as_str(), *ACCESS_TOKEN_TTL)
+        .await
+        .map_err(|_| RefreshError::Database)
+}
@@ -0,0 +1,518 @@
+use crate::auth::cache::invalidate_user;
+use crate::auth::signed::constant_time_eq;
+use crate::auth::{create_session, generate_token, IssuedTokens, RequestInfo, ACCESS_TOKEN_TTL, URL_SAFE_ENGINE};
+use crate::connections::PubSub;
+use crate::graphql::roles::Role;
+use crate::mail::APP_URL;
+use crate::models::user::{IdentityEntity, UserEntity};
+use crate::ModelFor;
+use chrono::Utc;
+use fred::interfaces::KeysInterface;
+use fred::types::Expiration;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use lazy_static::lazy_static;
+use mongodb::bson::{doc, to_bson, DateTime};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env::var;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::{OnceCell, RwLock};
+use url::Url;
+
+// Seconds a login may take at the identity provider
+pub const OIDC_STATE_TTL: i64 = 600;
+// Cookie that ties a pending login to the browser that started it
+pub const OIDC_BINDING_COOKIE: &str = "oidc_binding";
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    // Not needed for public clients, PKCE protects the code exchange
+    pub client_secret: Option<String>,
+    // Callback route of this api as registered at the provider
+    pub redirect_url: String,
+    pub scopes: String,
+    // Create users for unknown identities instead of rejecting the login
+    pub auto_provision: bool,
+    pub groups_claim: String,
+    // IdP group and the role it grants. Mapped roles are kept in sync with the groups on every login.
+    pub role_mapping: Vec<(String, Role)>,
+    // Frontend page that receives the tokens in the url fragment
+    pub login_redirect: String,
+}
+
+fn parse_role(role: &str) -> Option<Role> {
+    [Role::Root, Role::Admin, Role::User]
+        .into_iter()
+        .find(|r| r.as_str().eq_ignore_ascii_case(role.trim()))
+}
+
+// `OIDC_ROLE_MAPPING=platform-admins=Admin,staff=User`
+fn parse_role_mapping(mapping: &str) -> Vec<(String, Role)> {
+    mapping
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (group, role) = entry
+                .rsplit_once('=')
+                .unwrap_or_else(|| panic!("OIDC_ROLE_MAPPING entry '{}' is not group=Role", entry));
+            let role = parse_role(role).unwrap_or_else(|| panic!("OIDC_ROLE_MAPPING has unknown role '{}'", role));
+            (group.trim().to_owned(), role)
+        })
+        .collect()
+}
+
+lazy_static! {
+    // OIDC login is enabled by setting an issuer
+    pub static ref OIDC_CONFIG: Option<OidcConfig> = var("OIDC_ISSUER").ok().map(|issuer| OidcConfig {
+        issuer: issuer.trim_end_matches('/').to_owned(),
+        client_id: var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID not set in environment"),
+        client_secret: var("OIDC_CLIENT_SECRET").ok(),
+        redirect_url: var("OIDC_REDIRECT_URL").expect("OIDC_REDIRECT_URL not set in environment"),
+        scopes: var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".to_owned()),
+        auto_provision: var("OIDC_AUTO_PROVISION")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false),
+        groups_claim: var("OIDC_GROUPS_CLAIM").unwrap_or_else(|_| "groups".to_owned()),
+        role_mapping: parse_role_mapping(var("OIDC_ROLE_MAPPING").unwrap_or_default().as_str()),
+        login_redirect: var("OIDC_LOGIN_REDIRECT").unwrap_or_else(|_| format!("{}/oidc-callback", *APP_URL)),
+    });
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Cannot build http client");
+    static ref PROVIDER: OnceCell<ProviderMetadata> = OnceCell::new();
+    // Keys of the provider, fetched again when a token names an unknown key
+    static ref JWKS: RwLock<Option<JwkSet>> = RwLock::new(None);
+}
+
+// Fail on startup rather than on the first login
+pub fn ensure_oidc_config() {
+    lazy_static::initialize(&OIDC_CONFIG);
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Disabled,
+    InvalidState,
+    Storage,
+    Provider(anyhow::Error),
+    InvalidIdToken,
+    MissingEmail,
+    AccountExists,
+    TwoFactorEnabled,
+    NoAccount,
+    Database,
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Disabled => write!(f, "Login with an identity provider is not enabled"),
+            OidcError::InvalidState => write!(f, "The login is invalid or has expired, try again"),
+            OidcError::Storage => write!(f, "Cannot store the login state"),
+            OidcError::Provider(err) => write!(f, "The identity provider cannot be reached: {}", err),
+            OidcError::InvalidIdToken => write!(f, "The identity provider returned an invalid ID token"),
+            OidcError::MissingEmail => write!(f, "The identity provider did not share an email address"),
+            OidcError::AccountExists => write!(f, "An account with this email address exists but cannot be linked automatically"),
+            OidcError::TwoFactorEnabled => write!(f, "An account with this email address uses two-factor authentication and cannot be linked automatically"),
+            OidcError::NoAccount => write!(f, "No account is linked to this identity"),
+            OidcError::Database => write!(f, "Cannot write to database"),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for OidcError {
+    fn from(_: mongodb::error::Error) -> Self {
+        OidcError::Database
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(err: reqwest::Error) -> Self {
+        OidcError::Provider(err.into())
+    }
+}
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    // RS256 if the provider does not list any
+    #[serde(default)]
+    id_token_signing_alg_values_supported: Vec<String>,
+}
+
+async fn discover(config: &OidcConfig) -> Result<ProviderMetadata, OidcError> {
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let metadata = HTTP_CLIENT
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ProviderMetadata>()
+        .await?;
+    if metadata.issuer.trim_end_matches('/') != config.issuer {
+        return Err(OidcError::Provider(anyhow::anyhow!("Discovered issuer '{}' does not match", metadata.issuer)));
+    }
+    Ok(metadata)
+}
+
+// Discovered once, endpoints of a provider do not change while we run
+async fn provider(config: &OidcConfig) -> Result<&'static ProviderMetadata, OidcError> {
+    PROVIDER.get_or_try_init(|| discover(config)).await
+}
+
+// Kept in Redis between the redirect to the provider and the callback
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    nonce: String,
+    verifier: String,
+    // Hash of the binding cookie, a callback from another browser cannot complete the login
+    binding: String,
+}
+
+fn binding_hash(binding: &str) -> String {
+    base64::encode_engine(Sha256::digest(binding.as_bytes()), &URL_SAFE_ENGINE)
+}
+
+fn state_key(state: &str) -> String {
+    format!("oidc_state:{}", state)
+}
+
+// Start a login, returns the url of the provider to redirect the browser to and the value of the binding cookie
+pub async fn authorization_url(pubsub: &PubSub) -> Result<(String, String), OidcError> {
+    let config = OIDC_CONFIG.as_ref().ok_or(OidcError::Disabled)?;
+    let provider = provider(config).await?;
+
+    let state = generate_token();
+    let binding = generate_token();
+    let pending = PendingLogin {
+        nonce: generate_token(),
+        verifier: generate_token(),
+        binding: binding_hash(binding.as_str()),
+    };
+    let challenge = base64::encode_engine(Sha256::digest(pending.verifier.as_bytes()), &URL_SAFE_ENGINE);
+
+    pubsub
+        .publish
+        .set::<(), _, _>(
+            state_key(state.as_str()),
+            serde_json::to_string(&pending).map_err(|_| OidcError::Storage)?,
+            Some(Expiration::EX(OIDC_STATE_TTL)),
+            None,
+            false,
+        )
+        .await
+        .map_err(|_| OidcError::Storage)?;
+
+    let url = Url::parse_with_params(
+        provider.authorization_endpoint.as_str(),
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_url.as_str()),
+            ("scope", config.scopes.as_str()),
+            ("state", state.as_str()),
+            ("nonce", pending.nonce.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|err| OidcError::Provider(err.into()))?;
+    Ok((url.to_string(), binding))
+}
+
+// A state can only be used once, the key is deleted before the code is exchanged
+async fn take_pending_login(pubsub: &PubSub, state: &str, binding: &str) -> Result<PendingLogin, OidcError> {
+    let key = state_key(state);
+    let value = pubsub
+        .publish
+        .get::<Option<String>, _>(key.as_str())
+        .await
+        .map_err(|_| OidcError::InvalidState)?
+        .ok_or(OidcError::InvalidState)?;
+    let pending = match pubsub.publish.del::<i64, _>(key).await {
+        Ok(1) => serde_json::from_str::<PendingLogin>(value.as_str()).map_err(|_| OidcError::InvalidState)?,
+        _ => return Err(OidcError::InvalidState),
+    };
+    match constant_time_eq(pending.binding.as_bytes(), binding_hash(binding).as_bytes()) {
+        true => Ok(pending),
+        false => Err(OidcError::InvalidState),
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+async fn exchange_code(config: &OidcConfig, provider: &ProviderMetadata, code: &str, verifier: &str) -> Result<String, OidcError> {
+    let mut request = HTTP_CLIENT.post(provider.token_endpoint.as_str()).form(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", config.redirect_url.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", verifier),
+    ]);
+    if let Some(secret) = config.client_secret.as_ref() {
+        request = request.basic_auth(config.client_id.as_str(), Some(secret));
+    }
+
+    let response = request.send().await?.error_for_status()?.json::<TokenResponse>().await?;
+    Ok(response.id_token)
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    preferred_username: Option<String>,
+    // Holds the configurable groups claim
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl IdClaims {
+    fn groups(&self, claim: &str) -> Vec<String> {
+        match self.extra.get(claim) {
+            Some(serde_json::Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|g| g.as_str().map(|g| g.to_owned()))
+                .collect(),
+            Some(serde_json::Value::String(group)) => vec![group.clone()],
+            _ => vec![],
+        }
+    }
+}
+
+fn select_jwk(jwks: &JwkSet, kid: Option<&str>) -> Option<Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .cloned()
+}
+
+// Keys rotate rarely, the cached set is only refreshed for a key it does not know
+async fn find_jwk(provider: &ProviderMetadata, kid: Option<&str>) -> Result<Jwk, OidcError> {
+    if let Some(jwk) = JWKS.read().await.as_ref().and_then(|jwks| select_jwk(jwks, kid)) {
+        return Ok(jwk);
+    }
+    let jwks = HTTP_CLIENT
+        .get(provider.jwks_uri.as_str())
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await?;
+    let jwk = select_jwk(&jwks, kid);
+    *JWKS.write().await = Some(jwks);
+    jwk.ok_or(OidcError::InvalidIdToken)
+}
+
+// The algorithm of the key or the ones the provider signs ID tokens with, never the one a token claims.
+// Only keys published by the provider are accepted, never a shared secret.
+fn allowed_algorithms(provider: &ProviderMetadata, jwk: &Jwk) -> Vec<Algorithm> {
+    let algorithms = match jwk.common.algorithm {
+        Some(algorithm) => vec![algorithm],
+        None if provider.id_token_signing_alg_values_supported.is_empty() => vec![Algorithm::RS256],
+        None => provider
+            .id_token_signing_alg_values_supported
+            .iter()
+            .filter_map(|alg| alg.parse::<Algorithm>().ok())
+            .collect(),
+    };
+    algorithms
+        .into_iter()
+        .filter(|alg| !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512))
+        .collect()
+}
+
+async fn verify_id_token(config: &OidcConfig, provider: &ProviderMetadata, id_token: &str, nonce: &str) -> Result<IdClaims, OidcError> {
+    let header = decode_header(id_token).map_err(|_| OidcError::InvalidIdToken)?;
+    let jwk = find_jwk(provider, header.kid.as_deref()).await?;
+    let algorithms = allowed_algorithms(provider, &jwk);
+    if !algorithms.contains(&header.alg) {
+        return Err(OidcError::InvalidIdToken);
+    }
+    let key = DecodingKey::from_jwk(&jwk).map_err(|_| OidcError::InvalidIdToken)?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.algorithms = algorithms;
+    validation.set_audience(&[config.client_id.as_str()]);
+    validation.set_issuer(&[provider.issuer.as_str()]);
+    let claims = decode::<IdClaims>(id_token, &key, &validation)
+        .map_err(|_| OidcError::InvalidIdToken)?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::InvalidIdToken);
+    }
+    Ok(claims)
+}
+
+// Names are unique and 4 to 64 characters, taken ones get a random suffix
+async fn available_name(users: &ModelFor<UserEntity>, claims: &IdClaims) -> Result<String, OidcError> {
+    let base = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| claims.email.as_deref().and_then(|e| e.split('@').next()))
+        .unwrap_or("user")
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(48)
+        .collect::<String>();
+
+    for attempt in 0..5 {
+        let name = match attempt == 0 && base.chars().count() >= 4 {
+            true => base.clone(),
+            false => format!("{}-{}", base, &uuid::Uuid::new_v4().simple().to_string()[..6]),
+        };
+        if users.find_one(doc! { "name": name.as_str() }, None).await?.is_none() {
+            return Ok(name);
+        }
+    }
+    Err(OidcError::Database)
+}
+
+// The user linked to the identity. Verified addresses link to existing accounts, unknown identities are provisioned if enabled.
+async fn find_or_provision(users: &ModelFor<UserEntity>, config: &OidcConfig, issuer: &str, claims: &IdClaims) -> Result<UserEntity, OidcError> {
+    let linked = doc! { "identities": { "$elemMatch": { "issuer": issuer, "subject": claims.sub.as_str() } } };
+    if let Some(user) = users.find_one(linked, None).await? {
+        return Ok(user);
+    }
+
+    let email = claims.email.as_deref().ok_or(OidcError::MissingEmail)?;
+    let identity = IdentityEntity::new(issuer.to_owned(), claims.sub.clone());
+    let email_verified = claims.email_verified == Some(true);
+
+    if let Some(user) = users.find_one(doc! { "email_address": email }, None).await? {
+        // Both sides have to vouch for the address, otherwise an account registered with someone else's address could be taken over
+        if !email_verified || !user.email_verified {
+            return Err(OidcError::AccountExists);
+        }
+        // Linking would let the provider skip the second factor of the account
+        let result = users
+            .update_one(
+                doc! { "_id": user.id.unwrap(), "totp.enabled": { "$ne": true } },
+                doc! { "$push": { "identities": to_bson(&identity).map_err(|_| OidcError::Database)? }},
+                None,
+            )
+            .await?;
+        if result.matched_count == 0 {
+            return Err(OidcError::TwoFactorEnabled);
+        }
+        return Ok(user);
+    }
+
+    if !config.auto_provision {
+        return Err(OidcError::NoAccount);
+    }
+    let mut user = UserEntity::without_password(available_name(users, claims).await?, email.to_owned());
+    user.email_verified = email_verified;
+    user.identities.push(identity);
+    users.insert_one(&user, None).await?;
+    Ok(user)
+}
+
+// Roles that appear in the mapping follow the groups of the identity, other roles are left alone
+async fn sync_roles(users: &ModelFor<UserEntity>, pubsub: &PubSub, config: &OidcConfig, user: &mut UserEntity, groups: &[String]) -> Result<(), OidcError> {
+    if config.role_mapping.is_empty() {
+        return Ok(());
+    }
+
+    let mut roles = user
+        .roles
+        .iter()
+        .filter(|role| !config.role_mapping.iter().any(|(_, mapped)| mapped == *role))
+        .copied()
+        .collect::<Vec<Role>>();
+    for (group, role) in config.role_mapping.iter() {
+        if groups.contains(group) && !roles.contains(role) {
+            roles.push(*role);
+        }
+    }
+
+    let changed = roles.len() != user.roles.len() || roles.iter().any(|r| !user.roles.contains(r));
+    if changed {
+        users
+            .update_one(
+                doc! { "_id": user.id.unwrap() },
+                doc! { "$set": { "roles": roles.iter().map(|r| r.as_str()).collect::<Vec<&str>>() }},
+                None,
+            )
+            .await?;
+        invalidate_user(pubsub, &user.id.unwrap()).await;
+        user.roles = roles;
+    }
+    Ok(())
+}
+
+// Finish a login at the callback and issue the normal tokens. Second factors of linked identities are up to the identity provider,
+// accounts with two-factor authentication are never linked automatically.
+pub async fn complete_login(
+    users: &ModelFor<UserEntity>,
+    pubsub: &PubSub,
+    info: &RequestInfo,
+    code: &str,
+    state: &str,
+    binding: &str,
+) -> Result<IssuedTokens, OidcError> {
+    let config = OIDC_CONFIG.as_ref().ok_or(OidcError::Disabled)?;
+    let pending = take_pending_login(pubsub, state, binding).await?;
+    let provider = provider(config).await?;
+
+    let id_token = exchange_code(config, provider, code, pending.verifier.as_str()).await?;
+    let claims = verify_id_token(config, provider, id_token.as_str(), pending.nonce.as_str()).await?;
+
+    let mut user = find_or_provision(users, config, provider.issuer.as_str(), &claims).await?;
+    sync_roles(users, pubsub, config, &mut user, &claims.groups(config.groups_claim.as_str())).await?;
+
+    let tokens = create_session(users, &user, info, *ACCESS_TOKEN_TTL)
+        .await
+        .map_err(|_| OidcError::Database)?;
+    let _ = users
+        .update_one(
+            doc! { "_id": user.id.unwrap() },
+            doc! { "$set": { "last_login": DateTime::from_millis(Utc::now().timestamp_millis()) }},
+            None,
+        )
+        .await;
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_groups_and_roles() {
+        let mapping = parse_role_mapping("platform-admins=Admin, staff = user");
+        assert_eq!(mapping, vec![("platform-admins".to_owned(), Role::Admin), ("staff".to_owned(), Role::User)]);
+    }
+
+    #[test]
+    fn keeps_equal_signs_in_group_names() {
+        assert_eq!(parse_role_mapping("ou=admins=Root"), vec![("ou=admins".to_owned(), Role::Root)]);
+    }
+
+    #[test]
+    fn skips_empty_entries() {
+        assert!(parse_role_mapping("").is_empty());
+        assert_eq!(parse_role_mapping("staff=User,,"), vec![("staff".to_owned(), Role::User)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown role")]
+    fn rejects_unknown_roles() {
+        parse_role_mapping("staff=Owner");
+    }
+}
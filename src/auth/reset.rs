@@ -0,0 +1,101 @@
+use crate::auth::cache::invalidate_user;
+use crate::auth::hash::hash_token;
+use crate::auth::{generate_token, publish_session_revoked};
+use crate::connections::PubSub;
+use crate::mail::{Mail, Mailer, APP_URL};
+use crate::models::user::{PasswordResetEntity, UserEntity};
+use crate::password::hash_password;
+use crate::ModelFor;
+use chrono::{Duration, Utc};
+use lazy_static::lazy_static;
+use mongodb::bson::{doc, to_bson, DateTime, Document};
+use mongodb::options::FindOneOptions;
+use std::env::var;
+
+lazy_static! {
+    // Minutes a reset link stays valid
+    static ref PASSWORD_RESET_TTL: i64 = var("PASSWORD_RESET_TTL")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(60);
+}
+
+// Replace any pending reset of the user and mail the new token. Returns false for unknown addresses.
+pub async fn request_password_reset(users: &ModelFor<UserEntity>, mailer: &Mailer, email: &str) -> anyhow::Result<bool> {
+    let user = match users.find_one(doc! { "email_address": email }, None).await? {
+        Some(user) => user,
+        None => return Ok(false),
+    };
+
+    let token = generate_token();
+    let expire = DateTime::from_millis((Utc::now() + Duration::minutes(*PASSWORD_RESET_TTL)).timestamp_millis());
+    let reset = PasswordResetEntity::new(hash_token(&token), expire);
+    users
+        .update_one(
+            doc! { "_id": user.id.unwrap() },
+            doc! { "$set": { "password_reset": to_bson(&reset)? }},
+            None,
+        )
+        .await?;
+
+    let link = format!("{}/reset-password?token={}", *APP_URL, token);
+    let body = format!(
+        "Hello {},\n\na password reset has been requested for your account. Open the following link to choose a new password:\n\n{}\n\nThe link is valid for {} minutes. If you did not request a reset you can ignore this mail.\n",
+        user.name, link, *PASSWORD_RESET_TTL
+    );
+    mailer
+        .send(&Mail::new(user.email_address.as_str(), "Reset your password", body))
+        .await?;
+    Ok(true)
+}
+
+fn reset_filter(token: &str) -> Document {
+    let now = DateTime::from_millis(Utc::now().timestamp_millis());
+    doc! { "password_reset.token": hash_token(token), "password_reset.expire": { "$gte": now } }
+}
+
+// The user a reset token belongs to, if it is still valid. Needed to check the new password against the policy.
+pub async fn find_reset_user(users: &ModelFor<UserEntity>, token: &str) -> mongodb::error::Result<Option<UserEntity>> {
+    let options = FindOneOptions::builder()
+        .projection(doc! { "password_hash": 0 })
+        .build();
+    users.find_one(reset_filter(token), options).await
+}
+
+// Consume a reset token and set the new password. Every session and API token of the user is revoked.
+// Hashing is expensive, the token is looked up with `find_reset_user` before.
+pub async fn reset_password(users: &ModelFor<UserEntity>, pubsub: &PubSub, token: &str, password: String) -> anyhow::Result<bool> {
+    let filter = reset_filter(token);
+    let password_hash = hash_password(password).await?;
+
+    // Matching and clearing the token in one update keeps it single-use
+    let previous = users
+        .find_one_and_update(
+            filter,
+            doc! {
+                "$set": {
+                    "password_hash": password_hash,
+                    "access_token": [],
+                    "refresh_token": [],
+                    "sessions": [],
+                    "api_token": [],
+                },
+                "$unset": { "password_reset": "" },
+            },
+            None,
+        )
+        .await?;
+    let user = match previous {
+        Some(user) => user,
+        None => return Ok(false),
+    };
+
+    invalidate_user(pubsub, &user.id.unwrap()).await;
+    for session in user.sessions.iter() {
+        publish_session_revoked(pubsub, session.id.as_str()).await;
+    }
+    for token in user.api_token.iter() {
+        publish_session_revoked(pubsub, token.id.as_str()).await;
+    }
+    Ok(true)
+}
@@ -0,0 +1,250 @@
+use crate::auth::{CurrentSession, ACCESS_TOKEN_TTL, URL_SAFE_ENGINE};
+use crate::connections::PubSub;
+use crate::models::user::UserEntity;
+use crate::Role;
+use chrono::Utc;
+use dryoc::classic::crypto_generichash::crypto_generichash;
+use fred::interfaces::KeysInterface;
+use fred::types::Expiration;
+use lazy_static::lazy_static;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::env::var;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TokenMode {
+    // Random tokens looked up in the database
+    Opaque,
+    // Self-describing tokens verified by their signature
+    Signed,
+}
+
+lazy_static! {
+    pub static ref AUTH_TOKEN_MODE: TokenMode = match var("AUTH_TOKEN_MODE").as_deref() {
+        Ok("signed") => TokenMode::Signed,
+        Ok("opaque") | Err(_) => TokenMode::Opaque,
+        Ok(mode) => panic!("Unknown AUTH_TOKEN_MODE '{}', use 'opaque' or 'signed'", mode),
+    };
+    // Tests run in parallel threads and must not depend on the environment
+    static ref TOKEN_SIGNING_KEY: Vec<u8> = match cfg!(test) {
+        true => b"test-only-signing-key-0123456789abcdef".to_vec(),
+        false => {
+            let key = var("TOKEN_SIGNING_KEY").expect("TOKEN_SIGNING_KEY not set in environment").into_bytes();
+            if key.len() < 32 || key.len() > 64 {
+                panic!("TOKEN_SIGNING_KEY must be between 32 and 64 bytes long");
+            }
+            key
+        }
+    };
+}
+
+const SIGNED_TOKEN_PREFIX: &str = "st1.";
+
+// Fail on startup rather than on the first token to sign or a misspelled token mode
+pub fn ensure_signing_key() {
+    lazy_static::initialize(&AUTH_TOKEN_MODE);
+    lazy_static::initialize(&TOKEN_SIGNING_KEY);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClaims {
+    pub sub: String,
+    pub sid: String,
+    pub name: String,
+    pub email: String,
+    pub verified: bool,
+    pub roles: Vec<Role>,
+    pub created: i64,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+impl TokenClaims {
+    pub fn new(user: &UserEntity, session: &str, expire: DateTime) -> Self {
+        Self {
+            sub: user.id.unwrap().to_hex(),
+            sid: session.to_owned(),
+            name: user.name.clone(),
+            email: user.email_address.clone(),
+            verified: user.email_verified,
+            roles: user.roles.clone(),
+            created: user.when_created.timestamp_millis(),
+            iat: Utc::now().timestamp_millis(),
+            exp: expire.timestamp_millis(),
+        }
+    }
+
+    // The user as of the time the token was issued
+    pub fn into_session(self) -> Option<(UserEntity, CurrentSession)> {
+        let id = ObjectId::parse_str(self.sub.as_str()).ok()?;
+        let user = UserEntity {
+            id: Some(id),
+            name: self.name,
+            email_address: self.email,
+            email_verified: self.verified,
+            roles: self.roles,
+            password_hash: String::new(),
+            access_token: vec![],
+            refresh_token: vec![],
+            sessions: vec![],
+            api_token: vec![],
+            password_reset: None,
+            totp: None,
+            identities: vec![],
+            when_created: DateTime::from_millis(self.created),
+            last_login: DateTime::from_millis(self.iat),
+            last_access: DateTime::from_millis(Utc::now().timestamp_millis()),
+        };
+        let session = CurrentSession {
+            id: self.sid,
+            user: id,
+            scopes: None,
+            vaults: None,
+        };
+        Some((user, session))
+    }
+}
+
+// The prefix is signed as well, so tokens of one kind cannot be used as another
+fn signature(prefix: &str, payload: &str) -> [u8; 32] {
+    let mut mac = [0u8; 32];
+    let input = format!("{}{}", prefix, payload);
+    crypto_generichash(&mut mac, input.as_bytes(), Some(TOKEN_SIGNING_KEY.as_slice())).expect("Cannot sign token");
+    mac
+}
+
+// Compare without leaking the position of the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn is_signed_token(token: &str) -> bool {
+    token.starts_with(SIGNED_TOKEN_PREFIX)
+}
+
+// `<prefix><payload>.<mac>`, both url safe base64 encoded
+pub fn sign_payload<T: Serialize>(prefix: &str, payload: &T) -> String {
+    let payload = base64::encode_engine(serde_json::to_vec(payload).unwrap(), &URL_SAFE_ENGINE);
+    let mac = base64::encode_engine(signature(prefix, payload.as_str()), &URL_SAFE_ENGINE);
+    format!("{}{}.{}", prefix, payload, mac)
+}
+
+// Check the signature of a token and decode its payload, expiry is up to the caller
+pub fn verify_payload<T: DeserializeOwned>(prefix: &str, token: &str) -> Option<T> {
+    let (payload, mac) = token.strip_prefix(prefix)?.split_once('.')?;
+    let mac = base64::decode_engine(mac, &URL_SAFE_ENGINE).ok()?;
+    if !constant_time_eq(&mac, &signature(prefix, payload)) {
+        return None;
+    }
+    serde_json::from_slice(&base64::decode_engine(payload, &URL_SAFE_ENGINE).ok()?).ok()
+}
+
+pub fn sign_token(claims: &TokenClaims) -> String {
+    sign_payload(SIGNED_TOKEN_PREFIX, claims)
+}
+
+// Check signature and expiry of an access token
+pub fn verify_token(token: &str) -> Option<TokenClaims> {
+    let claims: TokenClaims = verify_payload(SIGNED_TOKEN_PREFIX, token)?;
+    match claims.exp >= Utc::now().timestamp_millis() {
+        true => Some(claims),
+        false => None,
+    }
+}
+
+fn revocation_key(session: &str) -> String {
+    format!("revoked_session:{}", session)
+}
+
+// Signed tokens stay valid until they expire, revoked sessions are remembered for that long
+pub async fn revoke_signed_session(pubsub: &PubSub, session: &str) {
+    let ttl = (*ACCESS_TOKEN_TTL).max(60) * 60;
+    let _ = pubsub
+        .publish
+        .set::<(), _, _>(revocation_key(session), 1, Some(Expiration::EX(ttl)), None, false)
+        .await;
+}
+
+fn not_before_key(user: &ObjectId) -> String {
+    format!("signed_not_before:{}", user.to_hex())
+}
+
+// Tokens of a user issued until now are rejected, the client refreshes them and gets the current roles
+pub async fn reject_signed_tokens_before_now(pubsub: &PubSub, user: &ObjectId) {
+    let ttl = (*ACCESS_TOKEN_TTL).max(60) * 60;
+    let _ = pubsub
+        .publish
+        .set::<(), _, _>(not_before_key(user), Utc::now().timestamp_millis(), Some(Expiration::EX(ttl)), None, false)
+        .await;
+}
+
+// Fails closed like the revocation check
+pub async fn is_issued_too_early(pubsub: &PubSub, claims: &TokenClaims) -> bool {
+    let user = match ObjectId::parse_str(claims.sub.as_str()) {
+        Ok(user) => user,
+        Err(_) => return true,
+    };
+    match pubsub.publish.get::<Option<i64>, _>(not_before_key(&user)).await {
+        Ok(not_before) => not_before.map_or(false, |not_before| claims.iat < not_before),
+        Err(_) => true,
+    }
+}
+
+// Fails closed, a token is not accepted if redis cannot be asked
+pub async fn is_session_revoked(pubsub: &PubSub, session: &str) -> bool {
+    match pubsub.publish.exists::<u8, _>(revocation_key(session)).await {
+        Ok(count) => count > 0,
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        value: String,
+    }
+
+    fn signed(value: &str) -> String {
+        sign_payload("ts1.", &Payload { value: value.to_owned() })
+    }
+
+    #[test]
+    fn accepts_its_own_signature() {
+        let token = signed("hello");
+        let payload: Payload = verify_payload("ts1.", token.as_str()).unwrap();
+        assert_eq!(payload.value, "hello");
+    }
+
+    #[test]
+    fn rejects_a_tampered_payload() {
+        let token = signed("hello");
+        let (_, mac) = token.rsplit_once('.').unwrap();
+        let forged = base64::encode_engine(br#"{"value":"admin"}"#, &URL_SAFE_ENGINE);
+        let token = format!("ts1.{}.{}", forged, mac);
+        assert!(verify_payload::<Payload>("ts1.", token.as_str()).is_none());
+    }
+
+    #[test]
+    fn rejects_a_tampered_mac() {
+        let token = signed("hello");
+        let (head, mac) = token.rsplit_once('.').unwrap();
+        let mut mac = base64::decode_engine(mac, &URL_SAFE_ENGINE).unwrap();
+        mac[0] ^= 1;
+        let token = format!("{}.{}", head, base64::encode_engine(mac, &URL_SAFE_ENGINE));
+        assert!(verify_payload::<Payload>("ts1.", token.as_str()).is_none());
+        assert!(verify_payload::<Payload>("ts1.", head).is_none());
+    }
+
+    #[test]
+    fn rejects_tokens_of_another_kind() {
+        let token = signed("hello");
+        let renamed = token.replacen("ts1.", "tx1.", 1);
+        assert!(verify_payload::<Payload>("tx1.", renamed.as_str()).is_none());
+        assert!(verify_payload::<Payload>("tx1.", token.as_str()).is_none());
+    }
+}
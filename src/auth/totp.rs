@@ -0,0 +1,198 @@
+use crate::auth::hash::hash_token;
+use crate::auth::URL_SAFE_ENGINE;
+use crate::models::user::{TotpEntity, UserEntity};
+use crate::ModelFor;
+use base32::Alphabet;
+use chrono::Utc;
+use dryoc::classic::crypto_generichash::crypto_generichash;
+use dryoc::classic::crypto_secretbox::{crypto_secretbox_easy, crypto_secretbox_open_easy, Key, Nonce};
+use dryoc::constants::{CRYPTO_SECRETBOX_MACBYTES, CRYPTO_SECRETBOX_NONCEBYTES};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use sha1::Sha1;
+use std::env::var;
+
+lazy_static! {
+    static ref TOTP_ISSUER: String = var("TOTP_ISSUER").unwrap_or_else(|_| "API".to_owned());
+    // Secrets are encrypted with a key derived from TOTP_ENCRYPTION_KEY. It is kept apart from the
+    // token keys, so those can be rotated. Changing it disables two-factor authentication for everyone.
+    static ref TOTP_KEY: Key = {
+        let secret = var("TOTP_ENCRYPTION_KEY").expect("TOTP_ENCRYPTION_KEY not set in environment").into_bytes();
+        if secret.len() < 32 || secret.len() > 64 {
+            panic!("TOTP_ENCRYPTION_KEY must be between 32 and 64 bytes long");
+        }
+        let mut key = Key::default();
+        crypto_generichash(&mut key, b"totp-secret", Some(secret.as_slice())).expect("Cannot derive TOTP key");
+        key
+    };
+}
+
+// Fail on startup rather than on the first secret to encrypt
+pub fn ensure_totp_key() {
+    lazy_static::initialize(&TOTP_KEY);
+}
+
+// RFC 6238 defaults, supported by every authenticator app
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+// Accepted clock drift in steps
+const WINDOW: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    dryoc::rng::copy_randombytes(&mut bytes);
+    base32::encode(BASE32, &bytes)
+}
+
+// Nonce and ciphertext, base64 encoded
+pub fn encrypt_secret(secret: &str) -> String {
+    let mut nonce = Nonce::default();
+    dryoc::rng::copy_randombytes(&mut nonce);
+    let mut ciphertext = vec![0u8; secret.len() + CRYPTO_SECRETBOX_MACBYTES];
+    crypto_secretbox_easy(&mut ciphertext, secret.as_bytes(), &nonce, &TOTP_KEY).expect("Cannot encrypt secret");
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    base64::encode_engine(sealed, &URL_SAFE_ENGINE)
+}
+
+pub fn decrypt_secret(sealed: &str) -> Option<String> {
+    let sealed = base64::decode_engine(sealed, &URL_SAFE_ENGINE).ok()?;
+    if sealed.len() < CRYPTO_SECRETBOX_NONCEBYTES + CRYPTO_SECRETBOX_MACBYTES {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(CRYPTO_SECRETBOX_NONCEBYTES);
+    let mut nonce_bytes = Nonce::default();
+    nonce_bytes.copy_from_slice(nonce);
+    let mut secret = vec![0u8; ciphertext.len() - CRYPTO_SECRETBOX_MACBYTES];
+    crypto_secretbox_open_easy(&mut secret, ciphertext, &nonce_bytes, &TOTP_KEY).ok()?;
+    String::from_utf8(secret).ok()
+}
+
+fn code_at(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", value % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+// The time step a code belongs to, if it is valid around now
+pub fn verify_code(secret: &str, code: &str) -> Option<i64> {
+    let secret = base32::decode(BASE32, secret)?;
+    let now = Utc::now().timestamp() / STEP_SECONDS;
+    (now - WINDOW..=now + WINDOW).find(|step| code_at(&secret, *step) == code)
+}
+
+fn encode_uri_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    let issuer = encode_uri_component(TOTP_ISSUER.as_str());
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        encode_uri_component(account),
+        secret,
+        issuer,
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+// Plain codes for the user, only their hashes are stored
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 6];
+            dryoc::rng::copy_randombytes(&mut bytes);
+            base32::encode(BASE32, &bytes).to_lowercase()
+        })
+        .collect::<Vec<String>>();
+    let hashes = codes.iter().map(|c| hash_token(c)).collect();
+    (codes, hashes)
+}
+
+// Check a TOTP or recovery code of a user with enabled two-factor authentication. Both are
+// consumed atomically, a code cannot be used twice.
+pub async fn check_second_factor(users: &ModelFor<UserEntity>, user: &ObjectId, totp: &TotpEntity, code: &str) -> mongodb::error::Result<bool> {
+    let code = code.trim().replace(' ', "");
+
+    if let Some(step) = decrypt_secret(totp.secret.as_str()).and_then(|secret| verify_code(secret.as_str(), code.as_str())) {
+        let result = users
+            .update_one(
+                doc! { "_id": user, "totp.last_step": { "$lt": step } },
+                doc! { "$set": { "totp.last_step": step } },
+                None,
+            )
+            .await?;
+        return Ok(result.modified_count == 1);
+    }
+
+    let hash = hash_token(code.to_lowercase().as_str());
+    let result = users
+        .update_one(
+            doc! { "_id": user, "totp.recovery_codes": hash.as_str() },
+            doc! { "$pull": { "totp.recovery_codes": hash.as_str() } },
+            None,
+        )
+        .await?;
+    Ok(result.modified_count == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    // RFC 6238 appendix B, SHA1, truncated to six digits
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(code_at(SECRET, time / STEP_SECONDS), code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_codes_within_the_window() {
+        let secret = base32::encode(BASE32, SECRET);
+        let now = Utc::now().timestamp() / STEP_SECONDS;
+        // A step may pass between generating and verifying
+        let step = verify_code(secret.as_str(), code_at(SECRET, now).as_str()).unwrap();
+        assert!((now..=now + 1).contains(&step));
+        assert!(verify_code(secret.as_str(), code_at(SECRET, now - 1).as_str()).is_some());
+    }
+
+    #[test]
+    fn rejects_codes_outside_the_window() {
+        let secret = base32::encode(BASE32, SECRET);
+        let now = Utc::now().timestamp() / STEP_SECONDS;
+        assert!(verify_code(secret.as_str(), code_at(SECRET, now + 10).as_str()).is_none());
+        assert!(verify_code(secret.as_str(), code_at(SECRET, now - 10).as_str()).is_none());
+        assert!(verify_code("not base32!", "123456").is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::auth::signed::{sign_payload, verify_payload};
+use crate::mail::{Mail, Mailer, APP_URL};
+use crate::models::user::UserEntity;
+use chrono::{Duration, Utc};
+use lazy_static::lazy_static;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::env::var;
+
+lazy_static! {
+    // Hours a verification link stays valid
+    static ref EMAIL_VERIFICATION_TTL: i64 = var("EMAIL_VERIFICATION_TTL")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(48);
+    // Block sensitive operations for users without a verified email address
+    pub static ref REQUIRE_VERIFIED_EMAIL: bool = var("REQUIRE_VERIFIED_EMAIL")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+}
+
+const EMAIL_TOKEN_PREFIX: &str = "ev1.";
+
+// Bound to the address, so a link stops working once the address changes
+#[derive(Serialize, Deserialize)]
+struct EmailClaims {
+    sub: String,
+    email: String,
+    exp: i64,
+}
+
+pub fn email_verification_token(user: &UserEntity) -> String {
+    sign_payload(
+        EMAIL_TOKEN_PREFIX,
+        &EmailClaims {
+            sub: user.id.unwrap().to_hex(),
+            email: user.email_address.clone(),
+            exp: (Utc::now() + Duration::hours(*EMAIL_VERIFICATION_TTL)).timestamp_millis(),
+        },
+    )
+}
+
+// User and address of a valid verification token
+pub fn verify_email_token(token: &str) -> Option<(ObjectId, String)> {
+    let claims: EmailClaims = verify_payload(EMAIL_TOKEN_PREFIX, token)?;
+    if claims.exp < Utc::now().timestamp_millis() {
+        return None;
+    }
+    Some((ObjectId::parse_str(claims.sub.as_str()).ok()?, claims.email))
+}
+
+pub async fn send_verification_mail(mailer: &Mailer, user: &UserEntity) -> anyhow::Result<()> {
+    let link = format!("{}/verify-email?token={}", *APP_URL, email_verification_token(user));
+    let body = format!(
+        "Hello {},\n\nplease confirm your email address by opening the following link:\n\n{}\n\nThe link is valid for {} hours.\n",
+        user.name, link, *EMAIL_VERIFICATION_TTL
+    );
+    mailer
+        .send(&Mail::new(user.email_address.as_str(), "Confirm your email address", body))
+        .await
+}
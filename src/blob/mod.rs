@@ -0,0 +1,95 @@
+use crate::auth::signed::{sign_payload, verify_payload};
+use chrono::{Duration, Utc};
+use image::io::{Limits, Reader};
+use image::ImageOutputFormat;
+use lazy_static::lazy_static;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::env::var;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+lazy_static! {
+    pub static ref BLOB_DIR: PathBuf = PathBuf::from(var("BLOB_DIR").unwrap_or_else(|_| "./blobs".to_owned()));
+    // In bytes, defaults to 10 MiB
+    pub static ref BLOB_MAX_SIZE: usize = var("BLOB_MAX_SIZE")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(10 * 1024 * 1024);
+    // Minutes a signed blob url stays valid
+    static ref BLOB_URL_TTL: i64 = var("BLOB_URL_TTL")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(60);
+}
+
+const BLOB_URL_PREFIX: &str = "bl1.";
+
+// Grants access to a single blob and its thumbnail, without a token that could leak into logs
+#[derive(Serialize, Deserialize)]
+struct BlobClaims {
+    blob: String,
+    exp: i64,
+}
+
+fn blob_signature(id: &ObjectId) -> String {
+    sign_payload(
+        BLOB_URL_PREFIX,
+        &BlobClaims {
+            blob: id.to_hex(),
+            exp: (Utc::now() + Duration::minutes(*BLOB_URL_TTL)).timestamp_millis(),
+        },
+    )
+}
+
+// Only handed out to users that can access the blob, so it can be embedded where no header can be sent
+pub fn signed_blob_url(id: &ObjectId, thumbnail: bool) -> String {
+    match thumbnail {
+        true => format!("/blobs/{}/thumbnail?signature={}", id.to_hex(), blob_signature(id)),
+        false => format!("/blobs/{}?signature={}", id.to_hex(), blob_signature(id)),
+    }
+}
+
+pub fn verify_blob_signature(id: &ObjectId, signature: &str) -> bool {
+    match verify_payload::<BlobClaims>(BLOB_URL_PREFIX, signature) {
+        Some(claims) => claims.blob == id.to_hex() && claims.exp >= Utc::now().timestamp_millis(),
+        None => false,
+    }
+}
+
+// Bounding box of generated image thumbnails
+const THUMBNAIL_SIZE: u32 = 256;
+// Largest images a thumbnail is made of
+const THUMBNAIL_MAX_DIMENSION: u32 = 8192;
+const THUMBNAIL_MAX_ALLOC: u64 = 128 * 1024 * 1024;
+
+pub fn blob_path(id: &ObjectId) -> PathBuf {
+    BLOB_DIR.join(id.to_hex())
+}
+
+pub fn thumbnail_path(id: &ObjectId) -> PathBuf {
+    BLOB_DIR.join(format!("{}.thumb.png", id.to_hex()))
+}
+
+pub fn is_image(mime_type: &str) -> bool {
+    matches!(mime_type, "image/png" | "image/jpeg" | "image/gif" | "image/webp")
+}
+
+// Scale an image down to fit the thumbnail box and encode it as PNG. Images that declare
+// larger dimensions than the limits get no thumbnail, a small file could otherwise claim gigabytes.
+pub fn create_thumbnail(content: &[u8]) -> Option<Vec<u8>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(THUMBNAIL_MAX_DIMENSION);
+    limits.max_image_height = Some(THUMBNAIL_MAX_DIMENSION);
+    limits.max_alloc = Some(THUMBNAIL_MAX_ALLOC);
+
+    let mut reader = Reader::new(Cursor::new(content)).with_guessed_format().ok()?;
+    reader.limits(limits);
+    let image = reader.decode().ok()?;
+    let mut thumbnail = Cursor::new(vec![]);
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut thumbnail, ImageOutputFormat::Png)
+        .ok()?;
+    Some(thumbnail.into_inner())
+}
@@ -1,12 +1,22 @@
+use crate::auth::cache::invalidate_user;
+use crate::auth::lockout::{clear_failures, locked_accounts};
+use crate::connections::PubSub;
 use crate::graphql::admin::inputs::{AddRoleInput, RemoveRoleInput};
-use crate::graphql::guards::RoleGuard;
+use crate::graphql::admin::objects::LockedAccount;
+use crate::graphql::guards::{RoleGuard, ScopeGuard};
 use crate::graphql::roles::Role;
+use crate::graphql::scopes::Scope;
+use crate::graphql::user::find_user_by_name_or_id;
+use crate::graphql::user::objects::User;
 use crate::models::user::UserEntity;
 
-use async_graphql::{Context, Error, Object, Result};
+use async_graphql::{Context, Error, GuardExt, Object, Result};
+use futures::stream::TryStreamExt;
 use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
 use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use crate::ModelFor;
+use std::str::FromStr;
 
 pub mod inputs;
 pub mod objects;
@@ -20,12 +30,66 @@ pub struct AdminMutations;
 #[derive(Default)]
 pub struct AdminSubscriptions;
 
+#[Object]
+impl AdminQueries {
+    // Accounts that are locked after too many failed logins
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(ScopeGuard::new(Scope::Admin))")]
+    pub async fn locked_accounts(&self, ctx: &Context<'_>) -> Result<Vec<LockedAccount>> {
+        let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+        let pubsub = ctx.data::<PubSub>().unwrap();
+
+        let lockouts = locked_accounts(pubsub)
+            .await
+            .map_err(|_| Error::new("Cannot read lockouts"))?;
+        let ids = lockouts
+            .iter()
+            .filter_map(|l| ObjectId::from_str(l.account.as_str()).ok())
+            .collect::<Vec<ObjectId>>();
+        let locked_users: Vec<UserEntity> = users
+            .find(doc! { "_id": { "$in": ids } }, None)
+            .await
+            .map_err(|_| Error::new("Cannot read from database"))?
+            .try_collect()
+            .await
+            .map_err(|_| Error::new("Cannot read from database"))?;
+
+        Ok(lockouts
+            .into_iter()
+            .map(|l| LockedAccount {
+                user: locked_users
+                    .iter()
+                    .find(|u| u.id.map(|id| id.to_hex()) == Some(l.account.clone()))
+                    .cloned()
+                    .map(User::from),
+                account: l.account,
+                failures: l.failures,
+                retry_after: l.retry_after,
+            })
+            .collect())
+    }
+}
+
 #[Object]
 impl AdminMutations {
-    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    // Lift the lockout of a user, or of an account key as listed by lockedAccounts
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(ScopeGuard::new(Scope::Admin))")]
+    pub async fn unlock_account(&self, ctx: &Context<'_>, name_or_id: String) -> Result<bool> {
+        let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+        let pubsub = ctx.data::<PubSub>().unwrap();
+
+        let account = match find_user_by_name_or_id(users, name_or_id.as_str()).await {
+            Ok(user) => user.id.unwrap().to_hex(),
+            Err(_) => name_or_id,
+        };
+        clear_failures(pubsub, account.as_str()).await;
+        Ok(true)
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(ScopeGuard::new(Scope::Admin))")]
     pub async fn add_role(&self, ctx: &Context<'_>, args: AddRoleInput) -> Result<bool> {
         let user = ctx.data::<UserEntity>().unwrap();
         let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+        let pubsub = ctx.data::<PubSub>().unwrap();
 
         let filter = doc! { "$or": [{ "_id": args.name_or_id.clone() }, { "name": args.name_or_id.clone() }] };
         let update = doc! { "$addToSet": { "roles": args.role.as_str().clone() }};
@@ -53,6 +117,8 @@ impl AdminMutations {
                 .find_one_and_update(filter, update, options)
                 .await
             {
+                // Cached logins still carry the old roles
+                invalidate_user(pubsub, &old_record.id.unwrap()).await;
                 return Ok(true);
             }
         }
@@ -64,10 +130,11 @@ impl AdminMutations {
         )))
     }
 
-    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(ScopeGuard::new(Scope::Admin))")]
     pub async fn remove_role(&self, ctx: &Context<'_>, args: RemoveRoleInput) -> Result<bool> {
         let user = ctx.data::<UserEntity>().unwrap();
         let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+        let pubsub = ctx.data::<PubSub>().unwrap();
 
         let filter = doc! { "$or": [{ "_id": args.name_or_id.clone() }, { "name": args.name_or_id.clone() }] };
         let update = doc! { "$pull": { "roles": args.role.as_str().clone() }};
@@ -95,6 +162,8 @@ impl AdminMutations {
                 .find_one_and_update(filter, update, options)
                 .await
             {
+                // Cached logins still carry the old roles
+                invalidate_user(pubsub, &old_record.id.unwrap()).await;
                 return Ok(true);
             }
         }
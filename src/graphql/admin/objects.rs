@@ -1 +1,12 @@
+use crate::graphql::user::objects::User;
+use async_graphql::SimpleObject;
 
+#[derive(SimpleObject)]
+pub struct LockedAccount {
+    // User id, or the name that was tried for logins to unknown users
+    pub account: String,
+    pub user: Option<User>,
+    pub failures: i64,
+    // Seconds until the lockout expires
+    pub retry_after: i64,
+}
@@ -11,9 +11,59 @@ pub struct CreateChannelInput {
 }
 
 #[derive(InputObject)]
-pub struct SendChannelMessageInput {
+pub struct UpdateChannelInput {
     #[graphql(validator(min_length = 4, max_length = 64))]
+    pub name: Option<String>,
+
+    #[graphql(validator(min_length = 0, max_length = 1024))]
+    pub description: Option<String>,
+    pub public: Option<bool>,
+
+    pub channel: ID,
+}
+
+#[derive(InputObject)]
+pub struct SendChannelMessageInput {
+    // May only be empty when attachments are sent
+    #[graphql(default, validator(max_length = 4000))]
+    pub message: String,
+
+    pub channel: ID,
+
+    // Blobs uploaded through `POST /blobs`
+    #[graphql(default, validator(max_items = 10))]
+    pub attachments: Vec<ID>,
+
+    // Reply to the thread started by this message
+    pub parent_id: Option<ID>,
+}
+
+#[derive(InputObject)]
+pub struct EditChannelMessageInput {
+    #[graphql(validator(min_length = 1, max_length = 4000))]
     pub message: String,
 
+    pub id: ID,
+}
+
+#[derive(InputObject)]
+pub struct ReactionInput {
+    // Emoji shortcode like `:thumbsup:`
+    #[graphql(validator(min_length = 3, max_length = 64))]
+    pub emoji: String,
+
+    pub message: ID,
+}
+
+#[derive(InputObject)]
+pub struct ModerateUserInput {
     pub channel: ID,
+    pub user: String,
+
+    // In minutes, omit to restrict the user until it is lifted
+    #[graphql(validator(minimum = 1, maximum = 525600))]
+    pub duration: Option<i64>,
+
+    #[graphql(validator(max_length = 256))]
+    pub reason: Option<String>,
 }
@@ -1,26 +1,64 @@
 use async_graphql::async_stream::stream;
 use async_graphql::futures_util::Stream;
-use async_graphql::{Context, Error, Object, Result, Subscription, ID};
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::{Context, Error, ErrorExtensions, GuardExt, Object, Result, Subscription, ID};
 use chrono::Utc;
 use fred::interfaces::PubsubInterface;
 use fred::prelude::RedisValue;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use crate::graphql::channel::inputs::{CreateChannelInput, SendChannelMessageInput};
-use crate::graphql::channel::objects::{Channel, ChannelMessage};
-use crate::graphql::guards::{AuthGuard, RoleGuard};
+use crate::graphql::channel::inputs::{CreateChannelInput, EditChannelMessageInput, ReactionInput, SendChannelMessageInput, UpdateChannelInput};
+use crate::graphql::channel::moderation::{ensure_can_post, ensure_not_restricted};
+use crate::graphql::channel::objects::{Channel, ChannelEvent, ChannelMessage, ChannelRemoved, MessageCreated, MessageDeleted, MessageEdited, ReactionAdded, ReactionRemoved, UnreadCount};
+use crate::graphql::guards::{AuthGuard, RoleGuard, ScopeGuard, VerifiedGuard};
+use crate::graphql::scopes::Scope;
+use crate::graphql::notification::notify_mentions;
+use crate::graphql::pagination::{page_size, parse_cursor};
+use crate::graphql::user::find_user_by_name_or_id;
 use crate::graphql::user::objects::User;
-use crate::graphql::{roles, PubSub};
-use crate::models::channel::ChannelEntity;
+use crate::graphql::{roles, FromOid, PubSub};
+use crate::models::blob::BlobEntity;
+use crate::models::channel::{ChannelEntity, ChannelKind};
+use crate::models::message::{AttachmentEntity, ChannelMessageEntity};
+use crate::models::moderation::ModerationEntity;
+use crate::models::notification::NotificationEntity;
+use crate::models::read::ChannelReadEntity;
 use crate::models::user::UserEntity;
 use futures::stream::{StreamExt, TryStreamExt};
-use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::bson::{doc, oid::ObjectId, to_document, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument, UpdateModifications, UpdateOptions};
 use roles::Role;
+use serde::{Deserialize, Serialize};
 use crate::ModelFor;
 
 pub mod inputs;
+pub mod moderation;
 pub mod objects;
 
+// Topic of a user announcing new messages in their channels, used to push unread counts
+fn activity_topic(user: &ObjectId) -> String {
+  format!("channel_activity:{}", user.to_hex())
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChannelActivity {
+  channel: ObjectId,
+}
+
+// Typing notifications are only kept for a few seconds unless repeated
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn typing_topic(channel: &ObjectId) -> String {
+  format!("typing:{}", channel.to_hex())
+}
+
+#[derive(Serialize, Deserialize)]
+struct TypingActivity {
+  user: User,
+}
+
 #[derive(Default)]
 pub struct ChannelQueries;
 
@@ -30,13 +68,247 @@ pub struct ChannelMutations;
 #[derive(Default)]
 pub struct ChannelSubscriptions;
 
+// Load a channel by its id, distinguishing malformed from unknown ids
+pub async fn find_channel(channels: &ModelFor<ChannelEntity>, channel: &ID) -> Result<ChannelEntity> {
+  let id = ObjectId::from_str(channel.as_str())
+    .map_err(|_| Error::new("Invalid channel ID").extend_with(|_, e| e.set("code", "INVALID_ID")))?;
+
+  match channels.find_one(doc! { "_id": id }, None).await {
+    Ok(Some(entity)) => Ok(entity),
+    Ok(None) => Err(Error::new("Unknown channel ID").extend_with(|_, e| e.set("code", "NOT_FOUND"))),
+    Err(_) => Err(Error::new("Cannot read from database")),
+  }
+}
+
+// Give direct conversations of older versions their participant key, so the unique index can be built.
+// Only the oldest conversation of a set of users gets the key, duplicates stay readable but are not reused.
+pub async fn migrate_direct_keys(channels: &ModelFor<ChannelEntity>) -> mongodb::error::Result<()> {
+  let filter = doc! { "kind": ChannelKind::Direct.as_str(), "direct_key": { "$exists": false } };
+  let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+  let legacy: Vec<ChannelEntity> = channels.find(filter, options).await?.try_collect().await?;
+
+  for channel in legacy {
+    let key = ChannelEntity::direct_key(&channel.members);
+    if channels.find_one(doc! { "direct_key": key.as_str() }, None).await?.is_none() {
+      channels
+        .update_one(doc! { "_id": channel.id.unwrap() }, doc! { "$set": { "direct_key": key }}, None)
+        .await?;
+    }
+  }
+  Ok(())
+}
+
+// Older versions could store several read positions of a user in a channel, keep the furthest one
+pub async fn migrate_duplicate_reads(reads: &ModelFor<ChannelReadEntity>) -> mongodb::error::Result<()> {
+  let options = FindOptions::builder().sort(doc! { "last_read": -1 }).build();
+  let all: Vec<ChannelReadEntity> = reads.find(doc! {}, options).await?.try_collect().await?;
+
+  let mut seen = std::collections::HashSet::new();
+  let duplicates = all
+    .into_iter()
+    .filter(|r| !seen.insert((r.user, r.channel)))
+    .filter_map(|r| r.id)
+    .collect::<Vec<ObjectId>>();
+  if !duplicates.is_empty() {
+    reads.delete_many(doc! { "_id": { "$in": duplicates }}, None).await?;
+  }
+  Ok(())
+}
+
+// Archived channels are read-only
+fn ensure_writable(channel: &ChannelEntity) -> Result<()> {
+  if channel.archived {
+    return Err(Error::new("This channel is archived and read-only").extend_with(|_, e| e.set("code", "CHANNEL_ARCHIVED")));
+  }
+  Ok(())
+}
+
+// Messages of other users in a channel after the user's read position
+pub async fn count_unread(
+  reads: &ModelFor<ChannelReadEntity>,
+  messages: &ModelFor<ChannelMessageEntity>,
+  user: &ObjectId,
+  channel: &ObjectId,
+) -> Result<u64> {
+  let mut filter = doc! { "channel": channel, "author": { "$ne": user } };
+  match reads.find_one(doc! { "user": user, "channel": channel }, None).await {
+    Ok(Some(read)) => {
+      filter.insert("_id", doc! { "$gt": read.last_read });
+    }
+    Ok(None) => {}
+    Err(_) => return Err(Error::new("Cannot read from database")),
+  }
+
+  messages
+    .count_documents(filter, None)
+    .await
+    .map_err(|_| Error::new("Cannot read from database"))
+}
+
+// Only the channel owner and admins may manage a channel and its members
+fn ensure_channel_manager(channel: &ChannelEntity, user: &UserEntity) -> Result<()> {
+  if channel.is_direct() {
+    return Err(Error::new("Participants of a direct conversation cannot be changed"));
+  }
+  if channel.is_owner(&user.id.unwrap()) || user.roles.contains(&Role::Admin) {
+    return Ok(());
+  }
+  Err(Error::new("Only the channel owner can manage this channel"))
+}
+
+// Load a message together with its channel, the caller has to be able to read the channel
+async fn find_message(ctx: &Context<'_>, user: &UserEntity, message: &ID) -> Result<(ChannelMessageEntity, ChannelEntity)> {
+  let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+  let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+  let id = ObjectId::from_str(message.as_str())
+    .map_err(|_| Error::new("Invalid message ID").extend_with(|_, e| e.set("code", "INVALID_ID")))?;
+  let entity = match messages.find_one(doc! { "_id": id }, None).await {
+    Ok(Some(entity)) => entity,
+    Ok(None) => return Err(Error::new("Unknown message ID").extend_with(|_, e| e.set("code", "NOT_FOUND"))),
+    Err(_) => return Err(Error::new("Cannot read from database")),
+  };
+
+  let channel = find_channel(channels, &ID::from_object_id(entity.channel)).await?;
+  if !channel.can_read(&user.id.unwrap()) {
+    return Err(Error::new("You are not a member of this channel"));
+  }
+
+  Ok((entity, channel))
+}
+
+// Resolve uploaded blobs for attaching them to a message, only the uploader may attach a blob
+async fn find_attachments(ctx: &Context<'_>, user: &UserEntity, ids: &[ID]) -> Result<Vec<AttachmentEntity>> {
+  let blobs = ctx.data::<ModelFor<BlobEntity>>().unwrap();
+
+  let mut attachments = vec![];
+  for id in ids {
+    let blob_id = ObjectId::from_str(id.as_str())
+      .map_err(|_| Error::new("Invalid attachment ID").extend_with(|_, e| e.set("code", "INVALID_ID")))?;
+    match blobs.find_one(doc! { "_id": blob_id, "owner": user.id.unwrap() }, None).await {
+      Ok(Some(blob)) => attachments.push(AttachmentEntity::from(blob)),
+      Ok(None) => return Err(Error::new(format!("Unknown attachment '{}'", id.as_str())).extend_with(|_, e| e.set("code", "NOT_FOUND"))),
+      Err(_) => return Err(Error::new("Cannot read from database")),
+    }
+  }
+
+  Ok(attachments)
+}
+
+// Build the graphql representation of a stored message, resolving its author
+async fn message_object(ctx: &Context<'_>, entity: ChannelMessageEntity, channel: ChannelEntity) -> Result<ChannelMessage> {
+  let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+
+  let author = match users.find_one(doc! { "_id": entity.author }, None).await {
+    Ok(Some(author)) => User::from(author),
+    Ok(None) => User::placeholder(entity.author),
+    Err(_) => return Err(Error::new("Cannot read from database")),
+  };
+  Ok(ChannelMessage::from_entity(entity, author, Channel::from(channel)))
+}
+
+// Same as `message_object` for a list of messages, resolving all authors at once
+async fn message_objects(ctx: &Context<'_>, entities: Vec<ChannelMessageEntity>, channel: ChannelEntity) -> Result<Vec<ChannelMessage>> {
+  let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+
+  let authors = entities.iter().map(|e| e.author).collect::<Vec<ObjectId>>();
+  let authors: HashMap<ObjectId, User> = match users.find(doc! { "_id": { "$in": authors }}, None).await {
+    Ok(cursor) => cursor
+      .try_collect::<Vec<UserEntity>>()
+      .await?
+      .into_iter()
+      .map(|u| (u.id.unwrap(), User::from(u)))
+      .collect(),
+    Err(_) => return Err(Error::new("Cannot read from database")),
+  };
+
+  // Messages of removed users are kept, so pages stay complete and cursors do not skip
+  Ok(entities
+    .into_iter()
+    .map(|e| {
+      let author = authors.get(&e.author).cloned().unwrap_or_else(|| User::placeholder(e.author));
+      ChannelMessage::from_entity(e, author, Channel::from(channel.clone()))
+    })
+    .collect::<Vec<ChannelMessage>>())
+}
+
+// Subscribe to the events published for a channel
+async fn channel_events(pubsub: &PubSub, channel: &ObjectId) -> Result<impl Stream<Item=ChannelEvent>> {
+  let topic = channel.to_hex();
+  pubsub
+    .subscribe
+    .subscribe(topic.as_str())
+    .await
+    .map_err(|_| Error::new("Error subscribing to channel"))?;
+  let mut message_stream = pubsub.subscribe.on_message();
+  Ok(stream! {
+    while let Some((channel, message)) = message_stream.next().await {
+      // The subscriber connection is shared, skip messages of other channels
+      if channel != topic {
+        continue;
+      }
+      if let RedisValue::String(str) = message {
+        if let Ok(event) = serde_json::from_str::<ChannelEvent>(&str) {
+          let removed = matches!(event, ChannelEvent::Removed(_));
+          yield event;
+          if removed {
+            break;
+          }
+        }
+      }
+    }
+  })
+}
+
+async fn publish_event(pubsub: &PubSub, channel: &ObjectId, event: &ChannelEvent) {
+  let msg = serde_json::to_string::<ChannelEvent>(event).unwrap();
+  let _ = pubsub
+    .publish
+    .publish::<String, _, String>(channel.to_hex(), msg)
+    .await;
+}
+
+// Shortcodes look like `:thumbsup:` or `:+1:`
+fn is_emoji_shortcode(emoji: &str) -> bool {
+  emoji.len() > 2
+    && emoji.starts_with(':')
+    && emoji.ends_with(':')
+    && emoji[1..emoji.len() - 1]
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '+' || c == '-')
+}
+
+async fn apply_channel_update(
+  channels: &ModelFor<ChannelEntity>,
+  channel: &ChannelEntity,
+  update: impl Into<UpdateModifications>,
+) -> Result<Channel> {
+  let options = FindOneAndUpdateOptions::builder()
+    .return_document(ReturnDocument::After)
+    .build();
+
+  match channels
+    .find_one_and_update(doc! { "_id": channel.id.unwrap() }, update, options)
+    .await
+  {
+    Ok(Some(entity)) => Ok(Channel::from(entity)),
+    Ok(None) => Err(Error::new("Unknown channel ID")),
+    Err(_) => Err(Error::new("Cannot write to database")),
+  }
+}
+
 #[Object]
 impl ChannelQueries {
-  #[graphql(guard = "AuthGuard")]
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
   pub async fn list_channel(&self, ctx: &Context<'_>) -> Result<Vec<Channel>> {
+    let user = ctx.data::<UserEntity>().unwrap();
     let channel_collection = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
 
-    let filter = doc! { "public": true };
+    let filter = doc! {
+      "kind": { "$ne": ChannelKind::Direct.as_str() },
+      "archived": { "$ne": true },
+      "$or": [{ "public": true }, { "members": user.id.unwrap() }],
+    };
 
     if let Ok(cursor) = channel_collection.find(filter, None).await {
       let documents: Vec<_> = cursor.try_collect().await?;
@@ -48,18 +320,82 @@ impl ChannelQueries {
 
     Ok(vec![])
   }
+
+  // Direct conversations of the current user, most recently active first
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn my_conversations(&self, ctx: &Context<'_>) -> Result<Vec<Channel>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let channel_collection = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let filter = doc! { "kind": ChannelKind::Direct.as_str(), "members": user.id.unwrap() };
+    let options = FindOptions::builder()
+      .sort(doc! { "last_publish": -1 })
+      .build();
+
+    match channel_collection.find(filter, options).await {
+      Ok(cursor) => {
+        let documents: Vec<_> = cursor.try_collect().await?;
+        Ok(documents
+          .into_iter()
+          .map(|c| Channel::from(c))
+          .collect::<Vec<Channel>>())
+      }
+      Err(_) => Err(Error::new("Cannot read from database")),
+    }
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn thread_replies(
+    &self,
+    ctx: &Context<'_>,
+    message: ID,
+    first: Option<i32>,
+    after: Option<String>,
+  ) -> Result<Connection<String, ChannelMessage>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+
+    let (entity, channel) = find_message(ctx, user, &message).await?;
+    let limit = page_size(first);
+
+    let mut filter = doc! { "parent": entity.parent.unwrap_or(entity.id.unwrap()) };
+    if let Some(after) = parse_cursor(after)? {
+      filter.insert("_id", doc! { "$gt": after });
+    }
+    let options = FindOptions::builder()
+      .sort(doc! { "_id": 1 })
+      .limit(limit + 1)
+      .build();
+
+    let mut replies: Vec<ChannelMessageEntity> = match messages.find(filter, options).await {
+      Ok(cursor) => cursor.try_collect().await?,
+      Err(_) => return Err(Error::new("Cannot read from database")),
+    };
+    let has_next = replies.len() as i64 > limit;
+    replies.truncate(limit as usize);
+
+    let mut connection = Connection::new(false, has_next);
+    connection.edges.extend(
+      message_objects(ctx, replies, channel)
+        .await?
+        .into_iter()
+        .map(|m| Edge::new(m.id.to_string(), m)),
+    );
+    Ok(connection)
+  }
 }
 
 #[Object]
 impl ChannelMutations {
-  #[graphql(guard = "AuthGuard")]
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage)).and(VerifiedGuard)")]
   pub async fn create_channel(
     &self,
     ctx: &Context<'_>,
     channel: CreateChannelInput,
   ) -> Result<Channel> {
+    let user = ctx.data::<UserEntity>().unwrap();
     let channel_collection = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
-    let entity = ChannelEntity::new(channel.name, channel.description, channel.public);
+    let entity = ChannelEntity::new(channel.name, channel.description, channel.public, user.id.unwrap());
 
 
     match channel_collection
@@ -71,23 +407,276 @@ impl ChannelMutations {
     }
   }
 
-  #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+  // Open a direct conversation with other users, reusing an existing one with the same participants
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage)).and(VerifiedGuard)")]
+  pub async fn start_conversation(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(validator(min_items = 1, max_items = 16))] users: Vec<String>,
+  ) -> Result<Channel> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let user_collection = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let channel_collection = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let mut members = vec![user.id.unwrap()];
+    for name_or_id in users {
+      let participant = find_user_by_name_or_id(user_collection, name_or_id.as_str()).await?;
+      if !members.contains(&participant.id.unwrap()) {
+        members.push(participant.id.unwrap());
+      }
+    }
+    if members.len() < 2 {
+      return Err(Error::new("A conversation needs at least one other participant"));
+    }
+
+    // Upsert on the unique participant key, concurrent calls end up in the same conversation
+    let entity = ChannelEntity::direct(members);
+    let key = entity.direct_key.clone().unwrap();
+    let mut fields = to_document(&entity).map_err(|_| Error::new("Cannot write to database"))?;
+    fields.remove("direct_key");
+    let options = FindOneAndUpdateOptions::builder()
+      .upsert(true)
+      .return_document(ReturnDocument::After)
+      .build();
+    match channel_collection
+      .find_one_and_update(doc! { "direct_key": key.as_str() }, doc! { "$setOnInsert": fields }, options)
+      .await
+    {
+      Ok(Some(entity)) => Ok(Channel::from(entity)),
+      // A concurrent upsert won the race on the unique index, use its conversation
+      _ => match channel_collection.find_one(doc! { "direct_key": key.as_str() }, None).await {
+        Ok(Some(entity)) => Ok(Channel::from(entity)),
+        _ => Err(Error::new("Cannot write to database")),
+      },
+    }
+  }
+
+  #[graphql(guard = "RoleGuard::new(Role::Admin).and(ScopeGuard::new(Scope::Admin))")]
   pub async fn remove_channel(&self, ctx: &Context<'_>, channel: String) -> Result<bool> {
     let channel_collection = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+    let reads = ctx.data::<ModelFor<ChannelReadEntity>>().unwrap();
+    let moderation = ctx.data::<ModelFor<ModerationEntity>>().unwrap();
+    let notifications = ctx.data::<ModelFor<NotificationEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    let entity = find_channel(channel_collection, &ID::from(channel)).await?;
 
     match channel_collection
-      .delete_one(
-        doc! { "_id": ObjectId::from_str(channel.as_str()).unwrap() },
-        None,
-      )
+      .delete_one(doc! { "_id": entity.id.unwrap() }, None)
       .await
     {
-      Ok(_) => Ok(true),
+      Ok(result) if result.deleted_count == 1 => {
+        // Nothing may point at the channel afterwards
+        let filter = doc! { "channel": entity.id.unwrap() };
+        let _ = messages.delete_many(filter.clone(), None).await;
+        let _ = reads.delete_many(filter.clone(), None).await;
+        let _ = moderation.delete_many(filter.clone(), None).await;
+        let _ = notifications.delete_many(filter, None).await;
+        let event = ChannelEvent::Removed(ChannelRemoved { channel: ID::from_object_id(entity.id.unwrap()) });
+        publish_event(pubsub, &entity.id.unwrap(), &event).await;
+        Ok(true)
+      }
+      Ok(_) => Err(Error::new("Unknown channel ID").extend_with(|_, e| e.set("code", "NOT_FOUND"))),
       Err(_) => Err(Error::new("Cannot write to database")),
     }
   }
 
-  #[graphql(guard = "AuthGuard")]
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn update_channel(&self, ctx: &Context<'_>, args: UpdateChannelInput) -> Result<Channel> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &args.channel).await?;
+    ensure_channel_manager(&entity, user)?;
+
+    let mut changes = doc! {};
+    if let Some(name) = args.name {
+      changes.insert("name", name);
+    }
+    if let Some(description) = args.description {
+      changes.insert("description", description);
+    }
+    if let Some(public) = args.public {
+      changes.insert("public", public);
+    }
+    if changes.is_empty() {
+      return Ok(Channel::from(entity));
+    }
+
+    apply_channel_update(channels, &entity, doc! { "$set": changes }).await
+  }
+
+  // Archived channels are read-only and hidden from listings
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn archive_channel(
+    &self,
+    ctx: &Context<'_>,
+    channel: ID,
+    #[graphql(default = true)] archived: bool,
+  ) -> Result<Channel> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    ensure_channel_manager(&entity, user)?;
+
+    apply_channel_update(channels, &entity, doc! { "$set": { "archived": archived }}).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn transfer_channel_ownership(&self, ctx: &Context<'_>, channel: ID, user: String) -> Result<Channel> {
+    let current_user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    ensure_channel_manager(&entity, current_user)?;
+
+    let owner = find_user_by_name_or_id(users, user.as_str()).await?;
+    if entity.is_owner(&owner.id.unwrap()) {
+      return Err(Error::new(format!("User '{}' already owns this channel", owner.name)));
+    }
+
+    apply_channel_update(channels, &entity, doc! {
+      "$set": { "owner": owner.id.unwrap() },
+      "$addToSet": { "members": owner.id.unwrap() },
+    }).await
+  }
+
+  // Announce that the current user is typing, nothing is persisted
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelPost))")]
+  pub async fn set_typing(&self, ctx: &Context<'_>, channel: ID) -> Result<bool> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    if !entity.can_read(&user.id.unwrap()) {
+      return Err(Error::new("You are not a member of this channel"));
+    }
+    ensure_writable(&entity)?;
+    ensure_not_restricted(&entity, &user.id.unwrap())?;
+
+    let activity = serde_json::to_string(&TypingActivity { user: User::from(user.clone()) }).unwrap();
+    let _ = pubsub
+      .publish
+      .publish::<String, _, String>(typing_topic(&entity.id.unwrap()), activity)
+      .await;
+    Ok(true)
+  }
+
+  // Move the read position of the current user forward to the given message
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn mark_channel_read(&self, ctx: &Context<'_>, channel: ID, message_id: ID) -> Result<Channel> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let reads = ctx.data::<ModelFor<ChannelReadEntity>>().unwrap();
+
+    let (message, entity) = find_message(ctx, user, &message_id).await?;
+    if ID::from_object_id(entity.id.unwrap()) != channel {
+      return Err(Error::new("The message belongs to another channel"));
+    }
+
+    // One document per user and channel, `$max` never moves the position backwards
+    let options = UpdateOptions::builder().upsert(true).build();
+    let result = reads
+      .update_one(
+        doc! { "user": user.id.unwrap(), "channel": entity.id.unwrap() },
+        doc! {
+          "$max": { "last_read": message.id.unwrap() },
+          "$set": { "when_updated": DateTime::from_millis(Utc::now().timestamp_millis()) },
+        },
+        options,
+      )
+      .await;
+
+    match result {
+      Ok(_) => Ok(Channel::from(entity)),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn invite_to_channel(&self, ctx: &Context<'_>, channel: ID, user: String) -> Result<Channel> {
+    let current_user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    ensure_channel_manager(&entity, current_user)?;
+
+    let invitee = find_user_by_name_or_id(users, user.as_str()).await?;
+    if entity.is_banned(&invitee.id.unwrap()) {
+      return Err(Error::new(format!("User '{}' is banned from this channel", invitee.name)));
+    }
+    if entity.is_member(&invitee.id.unwrap()) {
+      return Err(Error::new(format!("User '{}' already is a member of this channel", invitee.name)));
+    }
+
+    apply_channel_update(channels, &entity, doc! { "$addToSet": { "members": invitee.id.unwrap() }}).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn remove_from_channel(&self, ctx: &Context<'_>, channel: ID, user: String) -> Result<Channel> {
+    let current_user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    ensure_channel_manager(&entity, current_user)?;
+
+    let member = find_user_by_name_or_id(users, user.as_str()).await?;
+    if entity.is_owner(&member.id.unwrap()) {
+      return Err(Error::new("The channel owner cannot be removed from the channel"));
+    }
+    if !entity.is_member(&member.id.unwrap()) {
+      return Err(Error::new(format!("User '{}' is not a member of this channel", member.name)));
+    }
+
+    apply_channel_update(channels, &entity, doc! { "$pull": { "members": member.id.unwrap() }}).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn join_channel(&self, ctx: &Context<'_>, channel: ID) -> Result<Channel> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    if entity.is_direct() || !entity.public {
+      return Err(Error::new("Private channels can only be joined by invitation"));
+    }
+    if entity.is_banned(&user.id.unwrap()) {
+      return Err(Error::new("You are banned from this channel").extend_with(|_, e| e.set("code", "CHANNEL_BANNED")));
+    }
+    ensure_writable(&entity)?;
+    if entity.is_member(&user.id.unwrap()) {
+      return Err(Error::new("You already are a member of this channel"));
+    }
+
+    apply_channel_update(channels, &entity, doc! { "$addToSet": { "members": user.id.unwrap() }}).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn leave_channel(&self, ctx: &Context<'_>, channel: ID) -> Result<bool> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    if entity.is_direct() {
+      return Err(Error::new("Participants of a direct conversation cannot be changed"));
+    }
+    if entity.is_owner(&user.id.unwrap()) {
+      return Err(Error::new("The channel owner cannot leave the channel"));
+    }
+    if !entity.is_member(&user.id.unwrap()) {
+      return Err(Error::new("You are not a member of this channel"));
+    }
+
+    apply_channel_update(channels, &entity, doc! { "$pull": { "members": user.id.unwrap() }}).await?;
+    Ok(true)
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelPost)).and(VerifiedGuard)")]
   pub async fn send_message_to_channel(
     &self,
     ctx: &Context<'_>,
@@ -97,56 +686,410 @@ impl ChannelMutations {
     let pubsub = ctx.data::<PubSub>().unwrap();
     let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
 
-    let id = ObjectId::from_str(args.channel.as_str()).map_err(|_| Error::new("Invalid channel ID"))?;
-
-    match channels.find_one(
-      doc! { "_id": id },
-      None,
-    ).await {
-      Ok(Some(channel)) => {
-        let message = ChannelMessage {
-          id: ID::from(ObjectId::new().to_hex()),
-          message: args.message,
-          send_to: Channel::from(channel.clone()),
-          send_from: User::from(user.clone()),
-          send_when: Utc::now().timestamp_millis(),
-        };
+    let channel = find_channel(channels, &args.channel).await?;
+    if !channel.can_read(&user.id.unwrap()) {
+      return Err(Error::new("You are not a member of this channel"));
+    }
+    ensure_writable(&channel)?;
+    ensure_can_post(ctx, &channel, user).await?;
 
-        let msg = serde_json::to_string::<ChannelMessage>(&message).unwrap();
-        let _ = pubsub
-          .publish
-          .publish::<String, _, String>(args.channel.as_str(), msg)
-          .await;
-        Ok(message)
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+
+    // Replies to replies are attached to the root of the thread
+    let parent = match args.parent_id {
+      Some(parent_id) => {
+        let (parent, _) = find_message(ctx, user, &parent_id).await?;
+        if parent.channel != channel.id.unwrap() {
+          return Err(Error::new("The parent message belongs to another channel"));
+        }
+        Some(parent.parent.unwrap_or(parent.id.unwrap()))
       }
-      Err(_) => Err(Error::new("Invalid channel ID")),
-      _ => Err(Error::new("Unknown channel ID"))
+      None => None,
+    };
+
+    let attachments = find_attachments(ctx, user, &args.attachments).await?;
+    if args.message.trim().is_empty() && attachments.is_empty() {
+      return Err(Error::new("A message needs either text or attachments"));
+    }
+
+    let entity = ChannelMessageEntity::new(channel.id.unwrap(), user.id.unwrap(), args.message, attachments, parent);
+    if messages.insert_one(&entity, None).await.is_err() {
+      return Err(Error::new("Cannot write to database"));
+    }
+    let _ = channels
+      .update_one(doc! { "_id": channel.id.unwrap() }, doc! { "$set": { "last_publish": entity.when_created }}, None)
+      .await;
+    // Only the other members are told, so subscribers never look at activity of foreign channels
+    let activity = serde_json::to_string(&ChannelActivity { channel: channel.id.unwrap() }).unwrap();
+    for member in channel.members.iter().filter(|m| **m != user.id.unwrap()) {
+      let _ = pubsub
+        .publish
+        .publish::<String, _, String>(activity_topic(member), activity.clone())
+        .await;
+    }
+    if let Some(parent) = parent {
+      let _ = messages
+        .update_one(
+          doc! { "_id": parent },
+          doc! {
+            "$inc": { "reply_count": 1 },
+            "$set": { "last_reply": entity.when_created },
+          },
+          None,
+        )
+        .await;
+    }
+
+    let _ = notify_mentions(ctx, user, entity.message.as_str(), Some(&channel), entity.id).await;
+
+    let message = ChannelMessage::from_entity(entity, User::from(user.clone()), Channel::from(channel.clone()));
+    publish_event(pubsub, &channel.id.unwrap(), &ChannelEvent::Created(MessageCreated { message: message.clone() })).await;
+    Ok(message)
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelPost))")]
+  pub async fn edit_channel_message(
+    &self,
+    ctx: &Context<'_>,
+    args: EditChannelMessageInput,
+  ) -> Result<ChannelMessage> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+
+    let (entity, channel) = find_message(ctx, user, &args.id).await?;
+    ensure_writable(&channel)?;
+    ensure_not_restricted(&channel, &user.id.unwrap())?;
+    if entity.author != user.id.unwrap() {
+      return Err(Error::new("Only the author can edit a message"));
     }
+
+    let options = FindOneAndUpdateOptions::builder()
+      .return_document(ReturnDocument::After)
+      .build();
+    let update = doc! { "$set": {
+      "message": args.message,
+      "when_edited": DateTime::from_millis(Utc::now().timestamp_millis()),
+    }};
+    let entity = match messages.find_one_and_update(doc! { "_id": entity.id.unwrap() }, update, options).await {
+      Ok(Some(entity)) => entity,
+      Ok(None) => return Err(Error::new("Unknown message ID")),
+      Err(_) => return Err(Error::new("Cannot write to database")),
+    };
+
+    let message = ChannelMessage::from_entity(entity, User::from(user.clone()), Channel::from(channel.clone()));
+    publish_event(pubsub, &channel.id.unwrap(), &ChannelEvent::Edited(MessageEdited { message: message.clone() })).await;
+    Ok(message)
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelPost))")]
+  pub async fn delete_channel_message(&self, ctx: &Context<'_>, message: ID) -> Result<bool> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+
+    let (entity, channel) = find_message(ctx, user, &message).await?;
+    ensure_writable(&channel)?;
+    if entity.author != user.id.unwrap() && !user.roles.contains(&Role::Admin) {
+      return Err(Error::new("Only the author or an admin can delete a message"));
+    }
+
+    match messages.delete_one(doc! { "_id": entity.id.unwrap() }, None).await {
+      Ok(result) if result.deleted_count == 1 => {
+        match entity.parent {
+          // The thread summary of the parent follows the remaining replies
+          Some(parent) => {
+            let options = FindOneOptions::builder().sort(doc! { "_id": -1 }).build();
+            let update = match messages.find_one(doc! { "parent": parent }, options).await {
+              Ok(Some(latest)) => doc! { "$inc": { "reply_count": -1 }, "$set": { "last_reply": latest.when_created }},
+              Ok(None) => doc! { "$set": { "reply_count": 0 }, "$unset": { "last_reply": "" }},
+              Err(_) => doc! { "$inc": { "reply_count": -1 }},
+            };
+            let options = FindOneAndUpdateOptions::builder()
+              .return_document(ReturnDocument::After)
+              .build();
+            if let Ok(Some(parent)) = messages.find_one_and_update(doc! { "_id": parent }, update, options).await {
+              if let Ok(message) = message_object(ctx, parent, channel.clone()).await {
+                publish_event(pubsub, &channel.id.unwrap(), &ChannelEvent::Edited(MessageEdited { message })).await;
+              }
+            }
+          }
+          // Removing the start of a thread removes its replies as well
+          None => {
+            let replies: Vec<ChannelMessageEntity> = match messages.find(doc! { "parent": entity.id.unwrap() }, None).await {
+              Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+              Err(_) => vec![],
+            };
+            let ids = replies.iter().filter_map(|r| r.id).collect::<Vec<ObjectId>>();
+            if !ids.is_empty() && messages.delete_many(doc! { "_id": { "$in": ids.clone() }}, None).await.is_ok() {
+              // Subscribers still show the replies until they learn about each of them
+              for id in ids {
+                publish_event(pubsub, &channel.id.unwrap(), &ChannelEvent::Deleted(MessageDeleted {
+                  id: ID::from_object_id(id),
+                  channel: ID::from_object_id(channel.id.unwrap()),
+                  parent_id: Some(ID::from_object_id(entity.id.unwrap())),
+                })).await;
+              }
+            }
+          }
+        }
+        publish_event(pubsub, &channel.id.unwrap(), &ChannelEvent::Deleted(MessageDeleted {
+          id: message,
+          channel: ID::from_object_id(channel.id.unwrap()),
+          parent_id: entity.parent.map(ID::from_object_id),
+        })).await;
+        Ok(true)
+      }
+      Ok(_) => Err(Error::new("Unknown message ID")),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelPost))")]
+  pub async fn add_reaction(&self, ctx: &Context<'_>, args: ReactionInput) -> Result<ChannelMessage> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+
+    if !is_emoji_shortcode(args.emoji.as_str()) {
+      return Err(Error::new(format!("'{}' is not a valid emoji shortcode", args.emoji)));
+    }
+
+    let (entity, channel) = find_message(ctx, user, &args.message).await?;
+    ensure_writable(&channel)?;
+    ensure_not_restricted(&channel, &user.id.unwrap())?;
+    if entity.has_reacted(&user.id.unwrap(), args.emoji.as_str()) {
+      return Err(Error::new(format!("You already reacted with '{}'", args.emoji)));
+    }
+
+    // Join an existing reaction first, otherwise start a new one
+    let joined = messages
+      .update_one(
+        doc! { "_id": entity.id.unwrap(), "reactions.emoji": args.emoji.clone() },
+        doc! { "$addToSet": { "reactions.$.users": user.id.unwrap() }},
+        None,
+      )
+      .await
+      .map_err(|_| Error::new("Cannot write to database"))?;
+    if joined.matched_count == 0 {
+      messages
+        .update_one(
+          doc! { "_id": entity.id.unwrap(), "reactions.emoji": { "$ne": args.emoji.clone() }},
+          doc! { "$push": { "reactions": { "emoji": args.emoji.clone(), "users": [user.id.unwrap()] }}},
+          None,
+        )
+        .await
+        .map_err(|_| Error::new("Cannot write to database"))?;
+    }
+
+    let (entity, _) = find_message(ctx, user, &args.message).await?;
+    publish_event(pubsub, &channel.id.unwrap(), &ChannelEvent::ReactionAdded(ReactionAdded {
+      message: args.message,
+      channel: ID::from_object_id(channel.id.unwrap()),
+      parent_id: entity.parent.map(ID::from_object_id),
+      emoji: args.emoji,
+      user: User::from(user.clone()),
+    })).await;
+    message_object(ctx, entity, channel).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelPost))")]
+  pub async fn remove_reaction(&self, ctx: &Context<'_>, args: ReactionInput) -> Result<ChannelMessage> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+
+    let (entity, channel) = find_message(ctx, user, &args.message).await?;
+    ensure_writable(&channel)?;
+    if !entity.has_reacted(&user.id.unwrap(), args.emoji.as_str()) {
+      return Err(Error::new(format!("You did not react with '{}'", args.emoji)));
+    }
+
+    messages
+      .update_one(
+        doc! { "_id": entity.id.unwrap(), "reactions.emoji": args.emoji.clone() },
+        doc! { "$pull": { "reactions.$.users": user.id.unwrap() }},
+        None,
+      )
+      .await
+      .map_err(|_| Error::new("Cannot write to database"))?;
+    // Drop reactions nobody is left on
+    let _ = messages
+      .update_one(
+        doc! { "_id": entity.id.unwrap() },
+        doc! { "$pull": { "reactions": { "users": { "$size": 0 }}}},
+        None,
+      )
+      .await;
+
+    let (entity, _) = find_message(ctx, user, &args.message).await?;
+    publish_event(pubsub, &channel.id.unwrap(), &ChannelEvent::ReactionRemoved(ReactionRemoved {
+      message: args.message,
+      channel: ID::from_object_id(channel.id.unwrap()),
+      parent_id: entity.parent.map(ID::from_object_id),
+      emoji: args.emoji,
+      user: User::from(user.clone()),
+    })).await;
+    message_object(ctx, entity, channel).await
   }
 }
 
 #[Subscription]
 impl ChannelSubscriptions {
-  #[graphql(guard = "AuthGuard")]
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
   pub async fn listen_channel(
     &self,
     ctx: &Context<'_>,
     channel: ID,
-  ) -> impl Stream<Item=ChannelMessage> {
+  ) -> Result<impl Stream<Item=ChannelEvent>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    if !entity.can_read(&user.id.unwrap()) {
+      return Err(Error::new("You are not a member of this channel"));
+    }
+
+    channel_events(pubsub, &entity.id.unwrap()).await
+  }
+
+  // Only the replies to a single thread
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn listen_thread(
+    &self,
+    ctx: &Context<'_>,
+    message: ID,
+  ) -> Result<impl Stream<Item=ChannelEvent>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    let (entity, channel) = find_message(ctx, user, &message).await?;
+    if entity.parent.is_some() {
+      return Err(Error::new("This message is a reply, listen to its thread instead"));
+    }
+
+    let events = channel_events(pubsub, &channel.id.unwrap()).await?;
+    Ok(events.filter(move |event| {
+      let in_thread = event.thread() == Some(&message) || matches!(event, ChannelEvent::Removed(_));
+      async move { in_thread }
+    }))
+  }
+
+  // Updated unread counts whenever a message arrives in a channel the user belongs to
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn unread_counts(&self, ctx: &Context<'_>) -> Result<impl Stream<Item=UnreadCount>> {
+    let user = ctx.data::<UserEntity>().unwrap().id.unwrap();
     let pubsub = ctx.data::<PubSub>().unwrap();
+    let reads = ctx.data::<ModelFor<ChannelReadEntity>>().unwrap().clone();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap().clone();
+
+    let topic_name = activity_topic(&user);
     pubsub
       .subscribe
-      .subscribe(channel.as_str())
+      .subscribe(topic_name.as_str())
       .await
-        .expect("Error subscribing to channel");
+      .map_err(|_| Error::new("Error subscribing to channel activity"))?;
     let mut message_stream = pubsub.subscribe.on_message();
-    stream! {
-      while let Some((_channel, message)) = message_stream.next().await {
-        if let RedisValue::String(str) = message {
-          let message = serde_json::from_str::<ChannelMessage>(&str).unwrap();
-          yield message;
+    Ok(stream! {
+      while let Some((topic, message)) = message_stream.next().await {
+        if topic != topic_name {
+          continue;
+        }
+        let activity = match message {
+          RedisValue::String(str) => match serde_json::from_str::<ChannelActivity>(&str) {
+            Ok(activity) => activity,
+            Err(_) => continue,
+          },
+          _ => continue,
+        };
+        if let Ok(unread_count) = count_unread(&reads, &messages, &user, &activity.channel).await {
+          yield UnreadCount {
+            channel: ID::from_object_id(activity.channel),
+            unread_count,
+          };
         }
       }
+    })
+  }
+
+  // The other users currently typing in a channel
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn channel_typing(&self, ctx: &Context<'_>, channel: ID) -> Result<impl Stream<Item=Vec<User>>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    if !entity.can_read(&user.id.unwrap()) {
+      return Err(Error::new("You are not a member of this channel"));
     }
+
+    let topic = typing_topic(&entity.id.unwrap());
+    pubsub
+      .subscribe
+      .subscribe(topic.as_str())
+      .await
+      .map_err(|_| Error::new("Error subscribing to channel"))?;
+    let mut message_stream = pubsub.subscribe.on_message();
+    let current_user = ID::from_object_id(user.id.unwrap());
+
+    Ok(stream! {
+      let mut typing: HashMap<ID, (User, Instant)> = HashMap::new();
+      let mut interval = tokio::time::interval(Duration::from_secs(1));
+      loop {
+        let changed = tokio::select! {
+          next = message_stream.next() => match next {
+            Some((channel, RedisValue::String(str))) if channel == topic => {
+              match serde_json::from_str::<TypingActivity>(&str) {
+                Ok(activity) if activity.user.id != current_user => {
+                  let is_new = !typing.contains_key(&activity.user.id);
+                  typing.insert(activity.user.id.clone(), (activity.user, Instant::now()));
+                  is_new
+                }
+                _ => false,
+              }
+            }
+            Some(_) => false,
+            None => break,
+          },
+          _ = interval.tick() => {
+            let before = typing.len();
+            typing.retain(|_, (_, when)| when.elapsed() < TYPING_TIMEOUT);
+            typing.len() != before
+          }
+        };
+        if changed {
+          yield typing.values().map(|(u, _)| u.clone()).collect::<Vec<User>>();
+        }
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::is_emoji_shortcode;
+
+  #[test]
+  fn accepts_shortcodes() {
+    assert!(is_emoji_shortcode(":thumbsup:"));
+    assert!(is_emoji_shortcode(":+1:"));
+    assert!(is_emoji_shortcode(":-1:"));
+    assert!(is_emoji_shortcode(":face_with_tears_of_joy:"));
+    assert!(is_emoji_shortcode(":100:"));
+  }
+
+  #[test]
+  fn rejects_everything_else() {
+    assert!(!is_emoji_shortcode(""));
+    assert!(!is_emoji_shortcode("::"));
+    assert!(!is_emoji_shortcode(":"));
+    assert!(!is_emoji_shortcode("thumbsup"));
+    assert!(!is_emoji_shortcode(":thumbsup"));
+    assert!(!is_emoji_shortcode(":ThumbsUp:"));
+    assert!(!is_emoji_shortcode(":thumbs up:"));
+    assert!(!is_emoji_shortcode(":<script>:"));
+    assert!(!is_emoji_shortcode(":ü:"));
   }
 }
@@ -0,0 +1,239 @@
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::{Context, Error, ErrorExtensions, GuardExt, Object, Result, ID};
+use chrono::{Duration, Utc};
+
+use crate::graphql::channel::inputs::ModerateUserInput;
+use crate::graphql::channel::objects::{Channel, ModerationLogEntry};
+use crate::graphql::channel::{apply_channel_update, ensure_channel_manager, find_channel};
+use crate::graphql::guards::{AuthGuard, ScopeGuard};
+use crate::graphql::scopes::Scope;
+use crate::graphql::pagination::{page_size, parse_cursor};
+use crate::graphql::roles::Role;
+use crate::graphql::user::find_user_by_name_or_id;
+use crate::models::channel::{ChannelEntity, ChannelRestrictionEntity};
+use crate::models::message::ChannelMessageEntity;
+use crate::models::moderation::{ModerationAction, ModerationEntity};
+use crate::models::user::UserEntity;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Bson, DateTime, Document};
+use mongodb::options::{FindOneOptions, FindOptions};
+use crate::ModelFor;
+
+#[derive(Default)]
+pub struct ChannelModerationQueries;
+
+#[derive(Default)]
+pub struct ChannelModerationMutations;
+
+// Banned or muted users cannot post, edit, react or type
+pub fn ensure_not_restricted(channel: &ChannelEntity, user: &ObjectId) -> Result<()> {
+  if channel.is_banned(user) {
+    return Err(Error::new("You are banned from this channel").extend_with(|_, e| e.set("code", "CHANNEL_BANNED")));
+  }
+  if channel.is_muted(user) {
+    return Err(Error::new("You are muted in this channel").extend_with(|_, e| e.set("code", "CHANNEL_MUTED")));
+  }
+  Ok(())
+}
+
+// Reject messages of banned or muted users and enforce slow mode
+pub async fn ensure_can_post(ctx: &Context<'_>, channel: &ChannelEntity, user: &UserEntity) -> Result<()> {
+  let user_id = user.id.unwrap();
+  ensure_not_restricted(channel, &user_id)?;
+
+  // Channel managers are not slowed down
+  if channel.slow_mode <= 0 || channel.is_owner(&user_id) || user.roles.contains(&Role::Admin) {
+    return Ok(());
+  }
+
+  let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+  let options = FindOneOptions::builder().sort(doc! { "_id": -1 }).build();
+  let last = messages
+    .find_one(doc! { "channel": channel.id.unwrap(), "author": user_id }, options)
+    .await
+    .map_err(|_| Error::new("Cannot read from database"))?;
+
+  if let Some(last) = last {
+    let elapsed = (Utc::now().timestamp_millis() - last.when_created.timestamp_millis()) / 1000;
+    if elapsed < channel.slow_mode {
+      let retry_after = channel.slow_mode - elapsed;
+      return Err(Error::new(format!("Slow mode is enabled, wait {} seconds before sending another message", retry_after))
+        .extend_with(|_, e| {
+          e.set("code", "SLOW_MODE");
+          e.set("retryAfter", retry_after);
+        }));
+    }
+  }
+
+  Ok(())
+}
+
+async fn record(ctx: &Context<'_>, entry: ModerationEntity) -> Result<()> {
+  let log = ctx.data::<ModelFor<ModerationEntity>>().unwrap();
+  match log.insert_one(&entry, None).await {
+    Ok(_) => Ok(()),
+    Err(_) => Err(Error::new("Cannot write to database")),
+  }
+}
+
+// Expression replacing the restriction of a user in a mute or ban list, dropping expired ones on the way.
+// It is evaluated by the database in a pipeline update, so concurrent moderation of other users is not lost.
+fn restrict(field: &str, restriction: Option<&ChannelRestrictionEntity>, user: &ObjectId) -> Result<Document> {
+  let now = DateTime::from_millis(Utc::now().timestamp_millis());
+  let kept = doc! { "$filter": {
+    "input": { "$ifNull": [format!("${}", field), []] },
+    "cond": { "$and": [
+      { "$ne": ["$$this.user", user] },
+      { "$or": [{ "$eq": [{ "$ifNull": ["$$this.until", Bson::Null] }, Bson::Null] }, { "$gt": ["$$this.until", now] }] },
+    ]},
+  }};
+  let added = match restriction {
+    Some(restriction) => vec![to_bson(restriction).map_err(|_| Error::new("Cannot write to database"))?],
+    None => vec![],
+  };
+  Ok(doc! { "$concatArrays": [kept, { "$literal": added }] })
+}
+
+async fn moderate(ctx: &Context<'_>, args: ModerateUserInput, action: ModerationAction) -> Result<Channel> {
+  let current_user = ctx.data::<UserEntity>().unwrap();
+  let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+  let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+  let entity = find_channel(channels, &args.channel).await?;
+  ensure_channel_manager(&entity, current_user)?;
+
+  let target = find_user_by_name_or_id(users, args.user.as_str()).await?;
+  let target_id = target.id.unwrap();
+  if target_id == current_user.id.unwrap() || entity.is_owner(&target_id) {
+    return Err(Error::new("The channel owner and yourself cannot be moderated"));
+  }
+
+  let until = args
+    .duration
+    .map(|minutes| DateTime::from_millis((Utc::now() + Duration::minutes(minutes)).timestamp_millis()));
+  let restriction = ChannelRestrictionEntity::new(target_id, until);
+
+  let stage = match action {
+    ModerationAction::Mute => doc! { "muted": restrict("muted", Some(&restriction), &target_id)? },
+    ModerationAction::Unmute => {
+      if !entity.is_muted(&target_id) {
+        return Err(Error::new(format!("User '{}' is not muted", target.name)));
+      }
+      doc! { "muted": restrict("muted", None, &target_id)? }
+    }
+    // Banned users also lose their membership
+    ModerationAction::Ban => doc! {
+      "banned": restrict("banned", Some(&restriction), &target_id)?,
+      "members": { "$filter": { "input": { "$ifNull": ["$members", []] }, "cond": { "$ne": ["$$this", target_id] } } },
+    },
+    ModerationAction::Unban => {
+      if !entity.is_banned(&target_id) {
+        return Err(Error::new(format!("User '{}' is not banned", target.name)));
+      }
+      doc! { "banned": restrict("banned", None, &target_id)? }
+    }
+    ModerationAction::SlowMode => return Err(Error::new("Use setSlowMode to change slow mode")),
+  };
+
+  let channel = apply_channel_update(channels, &entity, vec![doc! { "$set": stage }]).await?;
+
+  let mut entry = ModerationEntity::new(entity.id.unwrap(), current_user.id.unwrap(), action);
+  entry.target = Some(target_id);
+  entry.until = until;
+  entry.reason = args.reason;
+  record(ctx, entry).await?;
+
+  Ok(channel)
+}
+
+#[Object]
+impl ChannelModerationQueries {
+  // Moderation actions of a channel, newest first. Only visible to the channel owner and admins.
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn channel_moderation_log(
+    &self,
+    ctx: &Context<'_>,
+    channel: ID,
+    first: Option<i32>,
+    after: Option<String>,
+  ) -> Result<Connection<String, ModerationLogEntry>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+    let log = ctx.data::<ModelFor<ModerationEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    ensure_channel_manager(&entity, user)?;
+    let limit = page_size(first);
+
+    let mut filter = doc! { "channel": entity.id.unwrap() };
+    if let Some(after) = parse_cursor(after)? {
+      filter.insert("_id", doc! { "$lt": after });
+    }
+    let options = FindOptions::builder()
+      .sort(doc! { "_id": -1 })
+      .limit(limit + 1)
+      .build();
+
+    let mut documents: Vec<ModerationEntity> = match log.find(filter, options).await {
+      Ok(cursor) => cursor.try_collect().await?,
+      Err(_) => return Err(Error::new("Cannot read from database")),
+    };
+    let has_next = documents.len() as i64 > limit;
+    documents.truncate(limit as usize);
+
+    let mut connection = Connection::new(false, has_next);
+    connection.edges.extend(
+      documents
+        .into_iter()
+        .map(ModerationLogEntry::from)
+        .map(|e| Edge::new(e.id.to_string(), e)),
+    );
+    Ok(connection)
+  }
+}
+
+#[Object]
+impl ChannelModerationMutations {
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn mute_user(&self, ctx: &Context<'_>, args: ModerateUserInput) -> Result<Channel> {
+    moderate(ctx, args, ModerationAction::Mute).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn unmute_user(&self, ctx: &Context<'_>, args: ModerateUserInput) -> Result<Channel> {
+    moderate(ctx, args, ModerationAction::Unmute).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn ban_user(&self, ctx: &Context<'_>, args: ModerateUserInput) -> Result<Channel> {
+    moderate(ctx, args, ModerationAction::Ban).await
+  }
+
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn unban_user(&self, ctx: &Context<'_>, args: ModerateUserInput) -> Result<Channel> {
+    moderate(ctx, args, ModerationAction::Unban).await
+  }
+
+  // Minimum seconds between two messages of the same user, 0 disables slow mode
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelManage))")]
+  pub async fn set_slow_mode(
+    &self,
+    ctx: &Context<'_>,
+    channel: ID,
+    #[graphql(validator(minimum = 0, maximum = 21600))] seconds: i64,
+  ) -> Result<Channel> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+
+    let entity = find_channel(channels, &channel).await?;
+    ensure_channel_manager(&entity, user)?;
+
+    let channel = apply_channel_update(channels, &entity, doc! { "$set": { "slow_mode": seconds }}).await?;
+
+    let mut entry = ModerationEntity::new(entity.id.unwrap(), user.id.unwrap(), ModerationAction::SlowMode);
+    entry.slow_mode = Some(seconds);
+    record(ctx, entry).await?;
+
+    Ok(channel)
+  }
+}
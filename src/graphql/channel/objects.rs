@@ -1,15 +1,32 @@
+use crate::blob::signed_blob_url;
+use crate::graphql::channel::count_unread;
 use crate::graphql::user::objects::User;
-use crate::models::channel::ChannelEntity;
-use async_graphql::{SimpleObject, ID};
+use crate::graphql::FromOid;
+use crate::models::channel::{ChannelEntity, ChannelKind};
+use crate::models::message::{AttachmentEntity, ChannelMessageEntity, ReactionEntity};
+use crate::models::moderation::{ModerationAction, ModerationEntity};
+use crate::models::read::ChannelReadEntity;
+use crate::models::user::UserEntity;
+use crate::ModelFor;
+use async_graphql::{ComplexObject, Context, Result, SimpleObject, Union, ID};
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 #[derive(Default, Clone, SimpleObject, Deserialize, Serialize)]
+#[graphql(complex)]
 pub struct Channel {
     pub id: ID,
 
     pub name: String,
     pub description: String,
     pub public: bool,
+    pub kind: ChannelKind,
+    pub archived: bool,
+    pub slow_mode: i64,
+
+    pub owner: Option<ID>,
+    pub members: Vec<ID>,
 
     pub when_created: i64,
     pub last_publish: i64,
@@ -23,6 +40,15 @@ impl From<ChannelEntity> for Channel {
             name: e.name,
             description: e.description,
             public: e.public,
+            kind: e.kind,
+            archived: e.archived,
+            slow_mode: e.slow_mode,
+            owner: e.owner.map(ID::from_object_id),
+            members: e
+                .members
+                .into_iter()
+                .map(ID::from_object_id)
+                .collect::<Vec<ID>>(),
             when_created: e.when_created.timestamp_millis(),
             last_publish: e.last_publish.timestamp_millis(),
             last_subscribe: e.last_subscribe.timestamp_millis(),
@@ -30,11 +56,206 @@ impl From<ChannelEntity> for Channel {
     }
 }
 
+#[ComplexObject]
+impl Channel {
+    // Messages of other users after the current user's read position
+    async fn unread_count(&self, ctx: &Context<'_>) -> Result<u64> {
+        match (ctx.data_opt::<UserEntity>(), ObjectId::from_str(self.id.as_str())) {
+            (Some(user), Ok(channel)) => {
+                let reads = ctx.data::<ModelFor<ChannelReadEntity>>().unwrap();
+                let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+                count_unread(reads, messages, &user.id.unwrap(), &channel).await
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct UnreadCount {
+    pub channel: ID,
+    pub unread_count: u64,
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub users: Vec<ID>,
+}
+
+impl From<ReactionEntity> for Reaction {
+    fn from(e: ReactionEntity) -> Self {
+        Reaction {
+            emoji: e.emoji,
+            count: e.users.len(),
+            users: e
+                .users
+                .into_iter()
+                .map(ID::from_object_id)
+                .collect::<Vec<ID>>(),
+        }
+    }
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: ID,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+}
+
+impl From<AttachmentEntity> for Attachment {
+    fn from(e: AttachmentEntity) -> Self {
+        Attachment {
+            url: signed_blob_url(&e.blob, false),
+            thumbnail_url: match e.has_thumbnail {
+                true => Some(signed_blob_url(&e.blob, true)),
+                false => None,
+            },
+            id: ID::from(e.blob.to_hex()),
+            file_name: e.file_name,
+            mime_type: e.mime_type,
+            size: e.size,
+        }
+    }
+}
+
 #[derive(Clone, SimpleObject, Serialize, Deserialize)]
 pub struct ChannelMessage {
     pub id: ID,
     pub message: String,
+    pub attachments: Vec<Attachment>,
     pub send_when: i64,
     pub send_from: User,
     pub send_to: Channel,
+    pub edited_when: Option<i64>,
+    pub reactions: Vec<Reaction>,
+
+    pub parent_id: Option<ID>,
+    pub reply_count: i64,
+    pub last_reply: Option<i64>,
+}
+
+impl ChannelMessage {
+    pub fn from_entity(e: ChannelMessageEntity, send_from: User, send_to: Channel) -> Self {
+        ChannelMessage {
+            id: ID::from_object_id(e.id.unwrap()),
+            message: e.message,
+            attachments: e
+                .attachments
+                .into_iter()
+                .map(Attachment::from)
+                .collect::<Vec<Attachment>>(),
+            send_when: e.when_created.timestamp_millis(),
+            send_from,
+            send_to,
+            edited_when: e.when_edited.map(|d| d.timestamp_millis()),
+            reactions: e
+                .reactions
+                .into_iter()
+                .map(Reaction::from)
+                .collect::<Vec<Reaction>>(),
+            parent_id: e.parent.map(ID::from_object_id),
+            reply_count: e.reply_count,
+            last_reply: e.last_reply.map(|d| d.timestamp_millis()),
+        }
+    }
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct ModerationLogEntry {
+    pub id: ID,
+    pub channel: ID,
+    pub actor: ID,
+    pub action: ModerationAction,
+    pub target: Option<ID>,
+    pub until: Option<i64>,
+    pub slow_mode: Option<i64>,
+    pub reason: Option<String>,
+    pub when_created: i64,
+}
+
+impl From<ModerationEntity> for ModerationLogEntry {
+    fn from(e: ModerationEntity) -> Self {
+        ModerationLogEntry {
+            id: ID::from_object_id(e.id.unwrap()),
+            channel: ID::from_object_id(e.channel),
+            actor: ID::from_object_id(e.actor),
+            action: e.action,
+            target: e.target.map(ID::from_object_id),
+            until: e.until.map(|d| d.timestamp_millis()),
+            slow_mode: e.slow_mode,
+            reason: e.reason,
+            when_created: e.when_created.timestamp_millis(),
+        }
+    }
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct MessageCreated {
+    pub message: ChannelMessage,
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct MessageEdited {
+    pub message: ChannelMessage,
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct MessageDeleted {
+    pub id: ID,
+    pub channel: ID,
+    pub parent_id: Option<ID>,
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct ReactionAdded {
+    pub message: ID,
+    pub channel: ID,
+    pub parent_id: Option<ID>,
+    pub emoji: String,
+    pub user: User,
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct ReactionRemoved {
+    pub message: ID,
+    pub channel: ID,
+    pub parent_id: Option<ID>,
+    pub emoji: String,
+    pub user: User,
+}
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct ChannelRemoved {
+    pub channel: ID,
+}
+
+#[derive(Clone, Union, Serialize, Deserialize)]
+pub enum ChannelEvent {
+    Created(MessageCreated),
+    Edited(MessageEdited),
+    Deleted(MessageDeleted),
+    ReactionAdded(ReactionAdded),
+    ReactionRemoved(ReactionRemoved),
+    // Last event of a channel, subscriptions end after it
+    Removed(ChannelRemoved),
+}
+
+impl ChannelEvent {
+    // The thread an event belongs to, if it concerns a reply
+    pub fn thread(&self) -> Option<&ID> {
+        match self {
+            ChannelEvent::Created(e) => e.message.parent_id.as_ref(),
+            ChannelEvent::Edited(e) => e.message.parent_id.as_ref(),
+            ChannelEvent::Deleted(e) => e.parent_id.as_ref(),
+            ChannelEvent::ReactionAdded(e) => e.parent_id.as_ref(),
+            ChannelEvent::ReactionRemoved(e) => e.parent_id.as_ref(),
+            ChannelEvent::Removed(_) => None,
+        }
+    }
 }
@@ -1,6 +1,11 @@
+use crate::auth::verification::REQUIRE_VERIFIED_EMAIL;
+use crate::auth::CurrentSession;
 use crate::graphql::roles::Role;
+use crate::graphql::scopes::Scope;
 use crate::models::user::UserEntity;
+use crate::ModelFor;
 use async_graphql::{Context, Error, Guard, Result};
+use mongodb::bson::doc;
 
 // General Guard to check if a user is authenticated
 pub struct AuthGuard;
@@ -43,3 +48,71 @@ impl Guard for RoleGuard {
         }
     }
 }
+
+// Guard to check that an API token has been granted a scope, compose it with `AuthGuard` or `RoleGuard`
+pub struct ScopeGuard {
+    pub scope: Scope,
+}
+
+impl ScopeGuard {
+    pub fn new(scope: Scope) -> Self {
+        Self { scope }
+    }
+}
+
+#[async_trait::async_trait]
+impl Guard for ScopeGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        match ctx.data_opt::<CurrentSession>().and_then(|s| s.scopes.as_ref()) {
+            Some(scopes) if !scopes.contains(&self.scope) => Err(Error::new(format!(
+                "The token is missing the scope '{}'.",
+                self.scope.as_str()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Guard for account management, which is not available to API tokens
+pub struct SessionGuard;
+#[async_trait::async_trait]
+impl Guard for SessionGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        match ctx.data_opt::<CurrentSession>() {
+            Some(session) if session.is_api_token() => Err(Error::new("API tokens cannot be used for this operation")),
+            _ => Ok(()),
+        }
+    }
+}
+
+// API tokens may be restricted to some vaults
+pub fn ensure_vault_access(ctx: &Context<'_>, vault: &str) -> Result<()> {
+    match ctx.data_opt::<CurrentSession>().and_then(|s| s.vaults.as_ref()) {
+        Some(vaults) if !vaults.iter().any(|v| v == vault) => {
+            Err(Error::new(format!("The token has no access to vault '{}'.", vault)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Guard for sensitive operations, which may require a verified email address
+pub struct VerifiedGuard;
+#[async_trait::async_trait]
+impl Guard for VerifiedGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let user = match ctx.data::<UserEntity>() {
+            Ok(user) if *REQUIRE_VERIFIED_EMAIL && !user.email_verified => user,
+            _ => return Ok(()),
+        };
+        // Signed tokens carry the state of when they were issued, the address may be verified by now
+        let users = ctx.data::<ModelFor<UserEntity>>()?;
+        match users
+            .count_documents(doc! { "_id": user.id.unwrap(), "email_verified": true }, None)
+            .await
+        {
+            Ok(count) if count > 0 => Ok(()),
+            Ok(_) => Err(Error::new("You need to verify your email address first")),
+            Err(_) => Err(Error::new("Cannot read from database")),
+        }
+    }
+}
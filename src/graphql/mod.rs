@@ -1,30 +1,51 @@
 pub mod admin;
 pub mod channel;
 pub mod guards;
+pub mod notification;
+pub mod pagination;
 pub mod roles;
+pub mod scopes;
+pub mod search;
+pub mod session;
 pub mod user;
 pub mod sync;
 
 use std::convert::From;
 
-use crate::graphql::admin::AdminMutations;
-use crate::graphql::channel::{ChannelMutations, ChannelQueries, ChannelSubscriptions};
+use crate::graphql::admin::{AdminMutations, AdminQueries};
+use crate::graphql::channel::moderation::{ChannelModerationMutations, ChannelModerationQueries};
+use crate::graphql::channel::{migrate_direct_keys, migrate_duplicate_reads, ChannelMutations, ChannelQueries, ChannelSubscriptions};
+use crate::graphql::notification::{NotificationMutations, NotificationQueries, NotificationSubscriptions};
+use crate::graphql::search::SearchQueries;
+use crate::graphql::session::SessionRevocation;
 use crate::graphql::user::{UserMutations, UserQueries};
 use crate::graphql::sync::{SyncMutations, SyncSubscriptions};
+use anyhow::Context as _;
 use async_graphql::*;
 
 use futures_util::stream::Stream;
 use mongodb::bson::oid::ObjectId;
 
-use mongodb::{Database};
+use mongodb::bson::{doc, Document};
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
 use std::time::Duration;
 
 use std::env::var;
 use std::sync::Arc;
 use lazy_static::lazy_static;
+use crate::auth::bootstrap::bootstrap_root;
+use crate::auth::hash::migrate_plain_tokens;
+use crate::auth::migrate_legacy_sessions;
 use crate::connections::PubSub;
+use crate::mail::build_mailer;
 use crate::ModelFor;
+use crate::models::blob::BlobEntity;
 use crate::models::channel::ChannelEntity;
+use crate::models::message::ChannelMessageEntity;
+use crate::models::moderation::ModerationEntity;
+use crate::models::notification::NotificationEntity;
+use crate::models::read::ChannelReadEntity;
 use crate::models::user::UserEntity;
 
 lazy_static! {
@@ -52,19 +73,21 @@ pub struct MutationRoot;
 pub struct SubscriptionRoot;
 
 #[derive(MergedObject, Default)]
-pub struct Queries(/*QueryRoot,*/ UserQueries, ChannelQueries);
+pub struct Queries(/*QueryRoot,*/ AdminQueries, UserQueries, ChannelQueries, ChannelModerationQueries, NotificationQueries, SearchQueries);
 
 #[derive(MergedObject, Default)]
 pub struct Mutations(
 /*  MutationRoot,*/
   AdminMutations,
   ChannelMutations,
+  ChannelModerationMutations,
   UserMutations,
-  SyncMutations
+  SyncMutations,
+  NotificationMutations
 );
 
 #[derive(MergedSubscription, Default)]
-pub struct Subscriptions(SubscriptionRoot, ChannelSubscriptions, SyncSubscriptions);
+pub struct Subscriptions(SubscriptionRoot, ChannelSubscriptions, SyncSubscriptions, NotificationSubscriptions);
 
 pub type GraphqlSchema = Schema<Queries, Mutations, Subscriptions>;
 
@@ -82,21 +105,104 @@ impl SubscriptionRoot {
   }
 }
 
-pub async fn build_schema(db: Database, pubsub: PubSub) -> GraphqlSchema {
-  Schema::build(
+pub async fn build_schema(db: Database, pubsub: PubSub) -> anyhow::Result<GraphqlSchema> {
+  let messages = ModelFor::<ChannelMessageEntity>::new(
+    Arc::new(db.clone()),
+    "channel_messages",
+  );
+  // Required by `$text` queries of the message search
+  messages
+    .create_index(IndexModel::builder().keys(doc! { "message": "text" }).build(), None)
+    .await
+    .context("Cannot create message search index")?;
+
+  let users = ModelFor::<UserEntity>::new(
+    Arc::new(db.clone()),
+    "users",
+  );
+  // Tokens of older versions were stored in plain text
+  migrate_plain_tokens(&users)
+    .await
+    .context("Cannot hash stored tokens")?;
+  migrate_legacy_sessions(&users)
+    .await
+    .context("Cannot migrate legacy sessions")?;
+  // Without a Root nobody could ever grant roles, the api is still usable though
+  let markers = ModelFor::<Document>::new(
+    Arc::new(db.clone()),
+    "bootstrap",
+  );
+  if let Err(err) = bootstrap_root(&users, &markers).await {
+    log::warn!("Cannot bootstrap Root user: {:#}", err);
+  }
+
+  let channels = ModelFor::<ChannelEntity>::new(
+    Arc::new(db.clone()),
+    "channel",
+  );
+  // Direct conversations are unique per set of participants
+  migrate_direct_keys(&channels)
+    .await
+    .context("Cannot migrate direct conversations")?;
+  channels
+    .create_index(
+      IndexModel::builder()
+        .keys(doc! { "direct_key": 1 })
+        .options(
+          IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! { "direct_key": { "$type": "string" } })
+            .build(),
+        )
+        .build(),
+      None,
+    )
+    .await
+    .context("Cannot create direct conversation index")?;
+
+  let reads = ModelFor::<ChannelReadEntity>::new(
+    Arc::new(db.clone()),
+    "channel_reads",
+  );
+  // Read positions are upserted per user and channel, duplicates of older versions block the index
+  let read_index = IndexModel::builder()
+    .keys(doc! { "channel": 1, "user": 1 })
+    .options(IndexOptions::builder().unique(true).build())
+    .build();
+  if reads.create_index(read_index.clone(), None).await.is_err() {
+    migrate_duplicate_reads(&reads)
+      .await
+      .context("Cannot remove duplicate read positions")?;
+    reads
+      .create_index(read_index, None)
+      .await
+      .context("Cannot create read position index")?;
+  }
+
+  Ok(Schema::build(
     Queries::default(),
     Mutations::default(),
     Subscriptions::default(),
   )
+  .extension(SessionRevocation)
   .data(pubsub)
+  .data(build_mailer())
   // Model
-  .data(ModelFor::<UserEntity>::new(
+  .data(users)
+  .data(channels)
+  .data(messages)
+  .data(reads)
+  .data(ModelFor::<ModerationEntity>::new(
     Arc::new(db.clone()),
-    "users",
+    "channel_moderation",
   ))
-  .data(ModelFor::<ChannelEntity>::new(
+  .data(ModelFor::<BlobEntity>::new(
     Arc::new(db.clone()),
-    "channel",
+    "blobs",
+  ))
+  .data(ModelFor::<NotificationEntity>::new(
+    Arc::new(db.clone()),
+    "notifications",
   ))
-  .finish()
+  .finish())
 }
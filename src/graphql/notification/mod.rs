@@ -0,0 +1,190 @@
+use async_graphql::async_stream::stream;
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::futures_util::Stream;
+use async_graphql::{Context, Error, GuardExt, Object, Result, Subscription, ID};
+use fred::interfaces::PubsubInterface;
+use fred::prelude::RedisValue;
+use std::str::FromStr;
+
+use crate::graphql::guards::{AuthGuard, ScopeGuard};
+use crate::graphql::notification::objects::Notification;
+use crate::graphql::pagination::{page_size, parse_cursor};
+use crate::graphql::scopes::Scope;
+use crate::graphql::PubSub;
+use crate::mentions::parse_mentions;
+use crate::models::channel::ChannelEntity;
+use crate::models::notification::NotificationEntity;
+use crate::models::user::UserEntity;
+use futures::stream::{StreamExt, TryStreamExt};
+use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::options::FindOptions;
+use crate::ModelFor;
+
+pub mod objects;
+
+// Length of the text excerpt stored with a notification
+const EXCERPT_LENGTH: usize = 140;
+
+#[derive(Default)]
+pub struct NotificationQueries;
+
+#[derive(Default)]
+pub struct NotificationMutations;
+
+#[derive(Default)]
+pub struct NotificationSubscriptions;
+
+fn notification_topic(user: &ObjectId) -> String {
+  format!("notifications:{}", user.to_hex())
+}
+
+// Notify every user mentioned as `@name` in a text. Mentions inside a channel are only
+// delivered to users that are able to read it.
+pub async fn notify_mentions(
+  ctx: &Context<'_>,
+  author: &UserEntity,
+  text: &str,
+  channel: Option<&ChannelEntity>,
+  message: Option<ObjectId>,
+) -> Result<()> {
+  let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+  let notifications = ctx.data::<ModelFor<NotificationEntity>>().unwrap();
+  let pubsub = ctx.data::<PubSub>().unwrap();
+
+  let names = parse_mentions(text);
+  if names.is_empty() {
+    return Ok(());
+  }
+
+  let mentioned: Vec<UserEntity> = match users.find(doc! { "name": { "$in": names }}, None).await {
+    Ok(cursor) => cursor.try_collect().await?,
+    Err(_) => return Err(Error::new("Cannot read from database")),
+  };
+  let excerpt = text.chars().take(EXCERPT_LENGTH).collect::<String>();
+
+  for user in mentioned {
+    let id = user.id.unwrap();
+    if id == author.id.unwrap() || channel.map_or(false, |c| !c.can_read(&id)) {
+      continue;
+    }
+
+    let entity = NotificationEntity::mention(
+      id,
+      author.id.unwrap(),
+      author.name.clone(),
+      channel.and_then(|c| c.id),
+      message,
+      excerpt.clone(),
+    );
+    if notifications.insert_one(&entity, None).await.is_err() {
+      return Err(Error::new("Cannot write to database"));
+    }
+
+    let msg = serde_json::to_string::<Notification>(&Notification::from(entity)).unwrap();
+    let _ = pubsub
+      .publish
+      .publish::<String, _, String>(notification_topic(&id), msg)
+      .await;
+  }
+
+  Ok(())
+}
+
+#[Object]
+impl NotificationQueries {
+  // Notifications of the current user, newest first
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn my_notifications(
+    &self,
+    ctx: &Context<'_>,
+    first: Option<i32>,
+    after: Option<String>,
+    #[graphql(default = false)] unread_only: bool,
+  ) -> Result<Connection<String, Notification>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let notifications = ctx.data::<ModelFor<NotificationEntity>>().unwrap();
+    let limit = page_size(first);
+
+    let mut filter = doc! { "user": user.id.unwrap() };
+    if unread_only {
+      filter.insert("read", false);
+    }
+    if let Some(after) = parse_cursor(after)? {
+      filter.insert("_id", doc! { "$lt": after });
+    }
+    let options = FindOptions::builder()
+      .sort(doc! { "_id": -1 })
+      .limit(limit + 1)
+      .build();
+
+    let mut documents: Vec<NotificationEntity> = match notifications.find(filter, options).await {
+      Ok(cursor) => cursor.try_collect().await?,
+      Err(_) => return Err(Error::new("Cannot read from database")),
+    };
+    let has_next = documents.len() as i64 > limit;
+    documents.truncate(limit as usize);
+
+    let mut connection = Connection::new(false, has_next);
+    connection.edges.extend(
+      documents
+        .into_iter()
+        .map(Notification::from)
+        .map(|n| Edge::new(n.id.to_string(), n)),
+    );
+    Ok(connection)
+  }
+}
+
+#[Object]
+impl NotificationMutations {
+  // Mark the given notifications, or all of them, as read. Returns the number of changed notifications.
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn mark_notifications_read(&self, ctx: &Context<'_>, ids: Option<Vec<ID>>) -> Result<u64> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let notifications = ctx.data::<ModelFor<NotificationEntity>>().unwrap();
+
+    let mut filter = doc! { "user": user.id.unwrap(), "read": false };
+    if let Some(ids) = ids {
+      let ids = ids
+        .iter()
+        .map(|id| ObjectId::from_str(id.as_str()))
+        .collect::<std::result::Result<Vec<ObjectId>, _>>()
+        .map_err(|_| Error::new("Invalid notification ID"))?;
+      filter.insert("_id", doc! { "$in": ids });
+    }
+
+    match notifications.update_many(filter, doc! { "$set": { "read": true }}, None).await {
+      Ok(result) => Ok(result.modified_count),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+}
+
+#[Subscription]
+impl NotificationSubscriptions {
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn notifications(&self, ctx: &Context<'_>) -> Result<impl Stream<Item=Notification>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    let topic = notification_topic(&user.id.unwrap());
+    pubsub
+      .subscribe
+      .subscribe(topic.as_str())
+      .await
+      .map_err(|_| Error::new("Error subscribing to notifications"))?;
+    let mut message_stream = pubsub.subscribe.on_message();
+    Ok(stream! {
+      while let Some((channel, message)) = message_stream.next().await {
+        if channel != topic {
+          continue;
+        }
+        if let RedisValue::String(str) = message {
+          if let Ok(notification) = serde_json::from_str::<Notification>(&str) {
+            yield notification;
+          }
+        }
+      }
+    })
+  }
+}
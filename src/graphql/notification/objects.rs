@@ -0,0 +1,35 @@
+use crate::graphql::FromOid;
+use crate::models::notification::{NotificationEntity, NotificationKind};
+use async_graphql::{SimpleObject, ID};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub struct Notification {
+    pub id: ID,
+    pub kind: NotificationKind,
+
+    pub from: ID,
+    pub from_name: String,
+    pub channel: Option<ID>,
+    pub message: Option<ID>,
+    pub excerpt: String,
+
+    pub read: bool,
+    pub when_created: i64,
+}
+
+impl From<NotificationEntity> for Notification {
+    fn from(e: NotificationEntity) -> Self {
+        Notification {
+            id: ID::from_object_id(e.id.unwrap()),
+            kind: e.kind,
+            from: ID::from_object_id(e.from),
+            from_name: e.from_name,
+            channel: e.channel.map(ID::from_object_id),
+            message: e.message.map(ID::from_object_id),
+            excerpt: e.excerpt,
+            read: e.read,
+            when_created: e.when_created.timestamp_millis(),
+        }
+    }
+}
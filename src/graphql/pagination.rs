@@ -0,0 +1,47 @@
+use async_graphql::{Error, Result};
+use mongodb::bson::oid::ObjectId;
+use std::str::FromStr;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+// Number of items to return for a `first` argument
+pub fn page_size(first: Option<i32>) -> i64 {
+  first
+    .map(|f| f as i64)
+    .unwrap_or(DEFAULT_PAGE_SIZE)
+    .clamp(1, MAX_PAGE_SIZE)
+}
+
+// Cursors are the hex encoded object id of the last item of a page
+pub fn parse_cursor(cursor: Option<String>) -> Result<Option<ObjectId>> {
+  match cursor {
+    Some(cursor) => ObjectId::from_str(cursor.as_str())
+      .map(Some)
+      .map_err(|_| Error::new("Invalid cursor")),
+    None => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn page_size_defaults_and_clamps() {
+    assert_eq!(page_size(None), DEFAULT_PAGE_SIZE);
+    assert_eq!(page_size(Some(10)), 10);
+    assert_eq!(page_size(Some(0)), 1);
+    assert_eq!(page_size(Some(-5)), 1);
+    assert_eq!(page_size(Some(1000)), MAX_PAGE_SIZE);
+  }
+
+  #[test]
+  fn parses_object_id_cursors() {
+    let id = ObjectId::new();
+    assert_eq!(parse_cursor(Some(id.to_hex())).unwrap(), Some(id));
+    assert_eq!(parse_cursor(None).unwrap(), None);
+    assert!(parse_cursor(Some("not a cursor".to_owned())).is_err());
+    assert!(parse_cursor(Some(String::new())).is_err());
+  }
+}
@@ -0,0 +1,26 @@
+use async_graphql::Enum;
+use serde::{Deserialize, Serialize};
+
+// Permissions of personal API tokens, sessions of a login are not restricted
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Scope {
+    VaultRead,
+    VaultWrite,
+    ChannelRead,
+    ChannelPost,
+    ChannelManage,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::VaultRead => "vault:read",
+            Scope::VaultWrite => "vault:write",
+            Scope::ChannelRead => "channel:read",
+            Scope::ChannelPost => "channel:post",
+            Scope::ChannelManage => "channel:manage",
+            Scope::Admin => "admin",
+        }
+    }
+}
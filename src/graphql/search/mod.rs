@@ -0,0 +1,140 @@
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::{Context, Error, GuardExt, Object, Result, ID};
+use std::collections::HashMap;
+
+use crate::graphql::channel::find_channel;
+use crate::graphql::channel::objects::{Channel, ChannelMessage};
+use crate::graphql::guards::{AuthGuard, ScopeGuard};
+use crate::graphql::pagination::{page_size, parse_cursor};
+use crate::graphql::scopes::Scope;
+use crate::graphql::search::objects::{MessageSearchResult, SearchHighlight};
+use crate::graphql::user::find_user_by_name_or_id;
+use crate::graphql::user::objects::User;
+use crate::models::channel::ChannelEntity;
+use crate::models::message::ChannelMessageEntity;
+use crate::models::user::UserEntity;
+use crate::search::{search_terms, snippet};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::FindOptions;
+use crate::ModelFor;
+
+pub mod objects;
+
+#[derive(Default)]
+pub struct SearchQueries;
+
+#[Object]
+impl SearchQueries {
+  // Full text search over messages of the channels the current user can read, newest first.
+  // Quoted parts of the query are matched as phrases, `before` and `after` are unix timestamps in milliseconds.
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::ChannelRead))")]
+  pub async fn search_messages(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(validator(min_length = 1, max_length = 256))] query: String,
+    channel: Option<ID>,
+    from: Option<String>,
+    before: Option<i64>,
+    after: Option<i64>,
+    first: Option<i32>,
+    cursor: Option<String>,
+  ) -> Result<Connection<String, MessageSearchResult>> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let channels = ctx.data::<ModelFor<ChannelEntity>>().unwrap();
+    let messages = ctx.data::<ModelFor<ChannelMessageEntity>>().unwrap();
+    let user_id = user.id.unwrap();
+
+    let readable: Vec<ChannelEntity> = match channel {
+      Some(channel) => vec![find_channel(channels, &channel).await?],
+      None => {
+        let filter = doc! {
+          "$or": [{ "public": true, "archived": { "$ne": true }}, { "members": user_id }],
+        };
+        match channels.find(filter, None).await {
+          Ok(cursor) => cursor.try_collect().await?,
+          Err(_) => return Err(Error::new("Cannot read from database")),
+        }
+      }
+    };
+    let readable: HashMap<ObjectId, ChannelEntity> = readable
+      .into_iter()
+      .filter(|c| c.can_read(&user_id))
+      .map(|c| (c.id.unwrap(), c))
+      .collect();
+    if readable.is_empty() {
+      return Ok(Connection::new(false, false));
+    }
+
+    let limit = page_size(first);
+    let mut filter = doc! {
+      "$text": { "$search": query.clone() },
+      "channel": { "$in": readable.keys().cloned().collect::<Vec<ObjectId>>() },
+    };
+    if let Some(from) = from {
+      let author = find_user_by_name_or_id(users, from.as_str()).await?;
+      filter.insert("author", author.id.unwrap());
+    }
+    let mut when = doc! {};
+    if let Some(before) = before {
+      when.insert("$lt", DateTime::from_millis(before));
+    }
+    if let Some(after) = after {
+      when.insert("$gt", DateTime::from_millis(after));
+    }
+    if !when.is_empty() {
+      filter.insert("when_created", when);
+    }
+    if let Some(cursor) = parse_cursor(cursor)? {
+      filter.insert("_id", doc! { "$lt": cursor });
+    }
+    let options = FindOptions::builder()
+      .sort(doc! { "_id": -1 })
+      .limit(limit + 1)
+      .build();
+
+    let mut found: Vec<ChannelMessageEntity> = match messages.find(filter, options).await {
+      Ok(cursor) => cursor.try_collect().await?,
+      Err(_) => return Err(Error::new("Cannot read from database")),
+    };
+    let has_next = found.len() as i64 > limit;
+    found.truncate(limit as usize);
+
+    let authors = found.iter().map(|m| m.author).collect::<Vec<ObjectId>>();
+    // Hashes of passwords and tokens are not needed to show an author
+    let options = FindOptions::builder()
+      .projection(doc! { "password_hash": 0, "refresh_token": 0, "api_token": 0 })
+      .build();
+    let authors: HashMap<ObjectId, User> = match users.find(doc! { "_id": { "$in": authors }}, options).await {
+      Ok(cursor) => cursor
+        .try_collect::<Vec<UserEntity>>()
+        .await?
+        .into_iter()
+        .map(|u| (u.id.unwrap(), User::from(u)))
+        .collect(),
+      Err(_) => return Err(Error::new("Cannot read from database")),
+    };
+
+    let terms = search_terms(query.as_str());
+    let mut connection = Connection::new(false, has_next);
+    connection.edges.extend(found.into_iter().filter_map(|entity| {
+      // Messages of removed accounts are kept, like in the channel history
+      let author = authors.get(&entity.author).cloned().unwrap_or_else(|| User::placeholder(entity.author));
+      let channel = Channel::from(readable.get(&entity.channel)?.clone());
+      let snippet = snippet(entity.message.as_str(), &terms);
+      let message = ChannelMessage::from_entity(entity, author, channel);
+
+      Some(Edge::new(message.id.to_string(), MessageSearchResult {
+        message,
+        snippet: snippet.text,
+        highlights: snippet
+          .highlights
+          .into_iter()
+          .map(|(offset, length)| SearchHighlight { offset, length })
+          .collect(),
+      }))
+    }));
+    Ok(connection)
+  }
+}
@@ -0,0 +1,16 @@
+use crate::graphql::channel::objects::ChannelMessage;
+use async_graphql::SimpleObject;
+
+#[derive(Clone, SimpleObject)]
+pub struct SearchHighlight {
+    // Character offset inside the snippet
+    pub offset: usize,
+    pub length: usize,
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct MessageSearchResult {
+    pub message: ChannelMessage,
+    pub snippet: String,
+    pub highlights: Vec<SearchHighlight>,
+}
@@ -0,0 +1,73 @@
+use crate::auth::{is_session_active, CurrentSession, SESSION_REVOKED_TOPIC};
+use crate::connections::PubSub;
+use crate::models::user::UserEntity;
+use crate::ModelFor;
+use async_graphql::async_stream::stream;
+use async_graphql::extensions::{Extension, ExtensionContext, ExtensionFactory, NextSubscribe};
+use async_graphql::futures_util::stream::{BoxStream, StreamExt};
+use async_graphql::{Response, ServerError};
+use fred::interfaces::PubsubInterface;
+use fred::prelude::RedisValue;
+use std::sync::Arc;
+
+// Ends subscriptions of a websocket connection once the session it was authenticated with is revoked
+pub struct SessionRevocation;
+
+impl ExtensionFactory for SessionRevocation {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(SessionRevocationExtension)
+    }
+}
+
+struct SessionRevocationExtension;
+
+// Resolves once the session shows up on the revocation topic
+async fn revoked(pubsub: PubSub, session: String) {
+    let mut message_stream = pubsub.subscribe.on_message();
+    if pubsub.subscribe.subscribe(SESSION_REVOKED_TOPIC).await.is_err() {
+        // Without the topic revocations are still caught when the next subscription starts
+        return async_graphql::futures_util::future::pending().await;
+    }
+    while let Some((topic, message)) = message_stream.next().await {
+        if topic != SESSION_REVOKED_TOPIC {
+            continue;
+        }
+        if let RedisValue::String(id) = message {
+            if id == session {
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for SessionRevocationExtension {
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        let session = ctx.data_opt::<CurrentSession>().cloned();
+        let users = ctx.data_opt::<ModelFor<UserEntity>>().cloned();
+        let pubsub = ctx.data_opt::<PubSub>().cloned();
+        let responses = next.run(ctx, stream);
+
+        let (session, users, pubsub) = match (session, users, pubsub) {
+            (Some(session), Some(users), Some(pubsub)) => (session, users, pubsub),
+            _ => return responses,
+        };
+
+        Box::pin(stream! {
+            // The connection may outlive its session, check it for every new subscription
+            if !is_session_active(&users, &session).await {
+                yield Response::from_errors(vec![ServerError::new("The session has been revoked", None)]);
+                return;
+            }
+            let mut responses = responses.take_until(revoked(pubsub, session.id.clone()));
+            while let Some(response) = responses.next().await {
+                yield response;
+            }
+        })
+    }
+}
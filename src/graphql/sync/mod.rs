@@ -1,6 +1,6 @@
 use async_graphql::async_stream::stream;
 use async_graphql::futures_util::Stream;
-use async_graphql::{Context, Error, Object, Result, Subscription, ID};
+use async_graphql::{Context, Error, GuardExt, Object, Result, Subscription, ID};
 use chrono::Utc;
 use fred::interfaces::PubsubInterface;
 use fred::prelude::RedisValue;
@@ -9,8 +9,9 @@ use async_graphql::parser::types::OperationType;
 
 use crate::graphql::channel::inputs::{CreateChannelInput, SendChannelMessageInput};
 use crate::graphql::channel::objects::{Channel, ChannelMessage};
-use crate::graphql::guards::{AuthGuard, RoleGuard};
+use crate::graphql::guards::{ensure_vault_access, AuthGuard, RoleGuard, ScopeGuard};
 use crate::graphql::user::objects::User;
+use crate::graphql::scopes::Scope;
 use crate::graphql::{roles, PubSub};
 use crate::models::channel::ChannelEntity;
 use crate::models::user::UserEntity;
@@ -37,10 +38,11 @@ pub struct SyncSubscriptions;
 
 #[Object]
 impl SyncMutations {
-  #[graphql(guard = "AuthGuard")]
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::VaultWrite))")]
   pub async fn create_file_or_folder(&self, ctx: &Context<'_>, vault_id: String, args: CreateArgs) -> Result<SyncEvent> {
     let user = ctx.data::<UserEntity>().unwrap();
     let pubsub = ctx.data::<PubSub>().unwrap();
+    ensure_vault_access(ctx, vault_id.as_str())?;
 
     let message = Create(CreateMessage {
       operation_type: args.object_type.clone(),
@@ -182,27 +184,34 @@ impl SyncMutations {
 
 #[Subscription]
 impl SyncSubscriptions {
-  #[graphql(guard = "AuthGuard")]
+  #[graphql(guard = "AuthGuard.and(ScopeGuard::new(Scope::VaultRead))")]
   pub async fn listen_sync_events(
     &self,
     ctx: &Context<'_>,
     vault_id: ID,
-  ) -> impl Stream<Item=SyncEvent> {
+  ) -> Result<impl Stream<Item=SyncEvent>> {
+    ensure_vault_access(ctx, vault_id.as_str())?;
 
     let pubsub = ctx.data::<PubSub>().unwrap();
+    let topic = vault_id.to_string();
     pubsub
       .subscribe
-      .subscribe(vault_id.as_str())
+      .subscribe(topic.as_str())
       .await
-      .expect("Error subscribing to vault events");
+      .map_err(|_| Error::new("Error subscribing to vault events"))?;
     let mut message_stream = pubsub.subscribe.on_message();
-    stream! {
-      while let Some((_channel, message)) = message_stream.next().await {
+    Ok(stream! {
+      while let Some((channel, message)) = message_stream.next().await {
+        // The subscriber connection is shared, skip events of other vaults
+        if channel != topic {
+          continue;
+        }
         if let RedisValue::String(str) = message {
-          let event = serde_json::from_str::<SyncEvent>(&str).unwrap();
-          yield event;
+          if let Ok(event) = serde_json::from_str::<SyncEvent>(&str) {
+            yield event;
+          }
         }
       }
-    }
+    })
   }
 }
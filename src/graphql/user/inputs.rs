@@ -1,5 +1,7 @@
 use async_graphql::*;
 
+use crate::graphql::scopes::Scope;
+
 #[derive(InputObject)]
 pub struct CreateUserInput {
   #[graphql(validator(min_length = 4, max_length = 64))]
@@ -20,7 +22,44 @@ pub struct CreateAccessToken {
   #[graphql(validator(min_length = 4, max_length = 128))]
   pub password: String,
 
-  // In minutes, 0 does not expire, 43200 = 1 Month
-  #[graphql(default = 60, validator(minimum = 0, maximum = 43200))]
-  pub expire: i64,
-}
\ No newline at end of file
+  // In minutes, defaults to ACCESS_TOKEN_TTL. Use the refresh token to get a new one.
+  #[graphql(validator(minimum = 1, maximum = 60))]
+  pub expire: Option<i64>,
+
+  // TOTP or recovery code, required once two-factor authentication is enabled
+  #[graphql(validator(max_length = 32))]
+  pub otp: Option<String>,
+}
+
+#[derive(InputObject)]
+pub struct ChangePasswordInput {
+  #[graphql(validator(min_length = 4, max_length = 128))]
+  pub current_password: String,
+
+  #[graphql(validator(min_length = 4, max_length = 128))]
+  pub new_password: String,
+}
+
+#[derive(InputObject)]
+pub struct ResetPasswordInput {
+  pub token: String,
+
+  #[graphql(validator(min_length = 4, max_length = 128))]
+  pub new_password: String,
+}
+
+#[derive(InputObject)]
+pub struct CreateApiTokenInput {
+  #[graphql(validator(min_length = 1, max_length = 64))]
+  pub name: String,
+
+  #[graphql(validator(min_items = 1))]
+  pub scopes: Vec<Scope>,
+
+  // Restrict the token to these vaults, all vaults if not set
+  pub vaults: Option<Vec<String>>,
+
+  // In days, does not expire if not set
+  #[graphql(validator(minimum = 1, maximum = 365))]
+  pub expire: Option<i64>,
+}
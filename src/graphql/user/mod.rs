@@ -1,13 +1,24 @@
-use async_graphql::{Context, Error, Object, Result};
+use async_graphql::{Context, Error, ErrorExtensions, GuardExt, Object, Result, ID};
 
-use crate::graphql::user::inputs::{CreateAccessToken, CreateUserInput};
-use crate::graphql::user::objects::{AccessToken, User};
-use crate::models::user::{AccessTokenEntity, UserEntity};
-use crate::password::verify_password;
+use crate::auth::cache::{acquire, invalidate_user};
+use crate::auth::lockout::{clear_failures, release_attempt, reserve_attempt, Attempt, LoginSubject};
+use crate::auth::reset::{find_reset_user, request_password_reset, reset_password};
+use crate::auth::totp::{check_second_factor, decrypt_secret, encrypt_secret, generate_recovery_codes, generate_secret, otpauth_uri, verify_code};
+use crate::auth::verification::{send_verification_mail, verify_email_token};
+use crate::auth::{create_api_token, create_session, publish_session_revoked, refresh_tokens, revoke_family, CurrentSession, RequestInfo, ACCESS_TOKEN_TTL};
+use crate::connections::PubSub;
+use crate::graphql::guards::{AuthGuard, SessionGuard, VerifiedGuard};
+use crate::graphql::user::inputs::{ChangePasswordInput, CreateAccessToken, CreateApiTokenInput, CreateUserInput, ResetPasswordInput};
+use crate::graphql::user::objects::{AccessToken, ApiToken, CreatedApiToken, Session, TotpEnrollment, User};
+use crate::mail::Mailer;
+use crate::models::user::{TotpEntity, UserEntity};
+use crate::password::policy::check_password;
+use crate::password::{hash_password, needs_rehash, verify_password};
 use crate::ModelFor;
-use chrono::{Duration, Utc};
-use mongodb::bson::{doc, DateTime};
-use uuid::Uuid;
+use chrono::Utc;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use std::str::FromStr;
 
 pub mod inputs;
 pub mod objects;
@@ -21,6 +32,114 @@ pub struct UserMutations;
 #[derive(Default)]
 pub struct UserSubscriptions;
 
+// Resolve a user either by its object id or by its name
+pub async fn find_user_by_name_or_id(users: &ModelFor<UserEntity>, name_or_id: &str) -> Result<UserEntity> {
+  let filter = match ObjectId::from_str(name_or_id) {
+    Ok(id) => doc! { "$or": [{ "_id": id }, { "name": name_or_id }] },
+    Err(_) => doc! { "name": name_or_id },
+  };
+
+  match users.find_one(filter, None).await {
+    Ok(Some(user)) => Ok(user),
+    Ok(None) => Err(Error::new(format!("User '{}' not found.", name_or_id))),
+    Err(_) => Err(Error::new("Cannot read from database")),
+  }
+}
+
+// New passwords have to satisfy the password policy, `identity` are the name and email address they must not contain
+fn ensure_strong_password(password: &str, identity: &[&str]) -> Result<()> {
+  check_password(password, identity).map_err(|err| Error::new(err.to_string()).extend_with(|_, e| e.set("code", "WEAK_PASSWORD")))
+}
+
+fn login_locked(retry_after: i64) -> Error {
+  Error::new(format!("Too many failed logins, wait {} seconds before trying again", retry_after))
+    .extend_with(|_, e| {
+      e.set("code", "LOGIN_LOCKED");
+      e.set("retryAfter", retry_after);
+    })
+}
+
+// Password and code checks are counted per account and per address. Attempts are reserved before
+// the credentials are checked, a failed attempt needs no further action.
+struct LoginAttempts {
+  account: Attempt,
+  ip: Option<Attempt>,
+}
+
+async fn reserve_login(pubsub: &PubSub, account: &str, ip: Option<&str>) -> Result<LoginAttempts> {
+  let ip = match ip {
+    Some(ip) => Some(reserve_attempt(pubsub, &LoginSubject::Ip(ip)).await.map_err(login_locked)?),
+    None => None,
+  };
+  match reserve_attempt(pubsub, &LoginSubject::Account(account)).await {
+    Ok(attempt) => Ok(LoginAttempts { account: attempt, ip }),
+    Err(retry_after) => {
+      if let Some(ip) = ip {
+        release_attempt(pubsub, ip).await;
+      }
+      Err(login_locked(retry_after))
+    }
+  }
+}
+
+impl LoginAttempts {
+  // The credentials were right, failures of the account are forgotten
+  async fn succeeded(self, pubsub: &PubSub, account: &str) {
+    clear_failures(pubsub, account).await;
+    if let Some(ip) = self.ip {
+      release_attempt(pubsub, ip).await;
+    }
+  }
+
+  // Neither right nor wrong, e.g. when the second factor is only asked for
+  async fn release(self, pubsub: &PubSub) {
+    release_attempt(pubsub, self.account).await;
+    if let Some(ip) = self.ip {
+      release_attempt(pubsub, ip).await;
+    }
+  }
+}
+
+// Reload the current user, signed access tokens only carry part of it
+async fn current_user(ctx: &Context<'_>) -> Result<UserEntity> {
+  let user = ctx.data::<UserEntity>().unwrap();
+  let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+
+  match users.find_one(doc! { "_id": user.id.unwrap() }, None).await {
+    Ok(Some(user)) => Ok(user),
+    Ok(None) => Err(Error::new("User not found.")),
+    Err(_) => Err(Error::new("Cannot read from database")),
+  }
+}
+
+async fn update_profile(users: &ModelFor<UserEntity>, user: &ObjectId, update: mongodb::bson::Document) -> Result<UserEntity> {
+  let options = FindOneAndUpdateOptions::builder()
+    .return_document(ReturnDocument::After)
+    .build();
+  match users.find_one_and_update(doc! { "_id": user }, update, options).await {
+    Ok(Some(entity)) => Ok(entity),
+    Ok(None) => Err(Error::new("User not found.")),
+    Err(_) => Err(Error::new("Cannot write to database")),
+  }
+}
+
+// Names and email addresses have to be unique
+async fn ensure_available(users: &ModelFor<UserEntity>, name: Option<&str>, email: Option<&str>) -> Result<()> {
+  let mut conditions = vec![];
+  if let Some(name) = name {
+    conditions.push(doc! { "name": name });
+  }
+  if let Some(email) = email {
+    conditions.push(doc! { "email_address": email });
+  }
+
+  match users.find_one(doc! { "$or": conditions }, None).await {
+    Ok(Some(_)) => Err(Error::new("Username or email has already been taken")),
+    Ok(None) => Ok(()),
+    Err(_) => Err(Error::new("Cannot read from database")),
+  }
+}
+
 #[Object]
 impl UserQueries {
   pub async fn get_user(&self, ctx: &Context<'_>, name: String) -> Result<User> {
@@ -30,31 +149,295 @@ impl UserQueries {
     }
     Err(Error::from("User not found."))
   }
+
+  // Active sessions of the current user, most recently used first
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn my_sessions(&self, ctx: &Context<'_>) -> Result<Vec<Session>> {
+    let user = current_user(ctx).await?;
+    let current = ctx.data_opt::<CurrentSession>().map(|s| s.id.as_str());
+
+    let mut sessions = user.sessions;
+    sessions.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+    Ok(sessions.into_iter().map(|s| Session::from_entity(s, current)).collect())
+  }
+
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn my_api_tokens(&self, ctx: &Context<'_>) -> Result<Vec<ApiToken>> {
+    let user = current_user(ctx).await?;
+    Ok(user.api_token.into_iter().map(ApiToken::from).collect())
+  }
 }
 
 #[Object]
 impl UserMutations {
   pub async fn create_user(&self, ctx: &Context<'_>, user: CreateUserInput) -> Result<User> {
-    let entity = UserEntity::new(user.name.clone(), user.email.clone(), user.password.clone());
+    ensure_strong_password(user.password.as_str(), &[user.name.as_str(), user.email.as_str()])?;
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let mailer = ctx.data::<Mailer>().unwrap();
+    ensure_available(users, Some(user.name.as_str()), Some(user.email.as_str())).await?;
+    let password_hash = hash_password(user.password).await.map_err(|_| Error::new("Cannot hash password"))?;
+    let entity = UserEntity::new(user.name, user.email, password_hash);
+
+    if users.insert_one(&entity, None).await.is_err() {
+      return Err(Error::new("Cannot write to database"));
+    }
+    // The account exists either way, the mail can be requested again
+    if let Err(err) = send_verification_mail(mailer, &entity).await {
+      log::warn!("Cannot send verification mail: {}", err);
+    }
+    Ok(User::from(entity))
+  }
+
+  pub async fn verify_email(&self, ctx: &Context<'_>, token: String) -> Result<User> {
     let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
-    if let Ok(val) = users
-      .find_one(
-        doc! { "$or": [{ "name": user.name }, { "email": user.email}] },
-        None,
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    let (id, email) = verify_email_token(token.as_str())
+      .ok_or_else(|| Error::new("The verification link is invalid or has expired"))?;
+    let options = FindOneAndUpdateOptions::builder()
+      .return_document(ReturnDocument::After)
+      .build();
+    match users
+      .find_one_and_update(
+        doc! { "_id": id, "email_address": email },
+        doc! { "$set": { "email_verified": true }},
+        options,
       )
       .await
     {
-      if val.is_some() {
-        return Err(Error::new("Username or email has already been taken"));
+      Ok(Some(entity)) => {
+        invalidate_user(pubsub, &id).await;
+        Ok(User::from(entity))
       }
+      Ok(None) => Err(Error::new("The verification link is invalid or has expired")),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  // Always reports success, whether an account exists for the address is not disclosed
+  pub async fn request_password_reset(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(validator(email, max_length = 64))] email: String,
+  ) -> Result<bool> {
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let mailer = ctx.data::<Mailer>().unwrap();
+
+    // Handled in the background, so the response time does not tell whether the address is known
+    if acquire(pubsub, format!("password_reset:{}", email), 60).await {
+      let users = users.clone();
+      let mailer = mailer.clone();
+      tokio::spawn(async move {
+        if let Err(err) = request_password_reset(&users, &mailer, email.as_str()).await {
+          log::warn!("Cannot request password reset: {}", err);
+        }
+      });
+    }
+    Ok(true)
+  }
+
+  // Set a new password with an emailed reset token, signing out everywhere
+  pub async fn reset_password(&self, ctx: &Context<'_>, args: ResetPasswordInput) -> Result<bool> {
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    let user = match find_reset_user(users, args.token.as_str()).await {
+      Ok(Some(user)) => user,
+      Ok(None) => return Err(Error::new("The reset link is invalid or has expired")),
+      Err(_) => return Err(Error::new("Cannot read from database")),
     };
+    ensure_strong_password(args.new_password.as_str(), &[user.name.as_str(), user.email_address.as_str()])?;
 
-    match users.insert_one(&entity, None).await {
-      Ok(_) => Ok(User::from(entity)),
+    match reset_password(users, pubsub, args.token.as_str(), args.new_password).await {
+      Ok(true) => Ok(true),
+      Ok(false) => Err(Error::new("The reset link is invalid or has expired")),
       Err(_) => Err(Error::new("Cannot write to database")),
     }
   }
 
+  // Other sessions are signed out, the current one and API tokens are kept
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn change_password(&self, ctx: &Context<'_>, args: ChangePasswordInput) -> Result<bool> {
+    let user = current_user(ctx).await?;
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let session = ctx.data::<CurrentSession>().unwrap();
+    let info = ctx.data_opt::<RequestInfo>().cloned().unwrap_or_default();
+
+    // Guesses count towards the same lockout as logins
+    let account = user.id.unwrap().to_hex();
+    let attempts = reserve_login(pubsub, account.as_str(), info.ip.as_deref()).await?;
+    if verify_password(user.password_hash.clone(), args.current_password).await.is_err() {
+      return Err(Error::new("The current password is wrong"));
+    }
+    attempts.succeeded(pubsub, account.as_str()).await;
+    ensure_strong_password(args.new_password.as_str(), &[user.name.as_str(), user.email_address.as_str()])?;
+    let password_hash = hash_password(args.new_password).await.map_err(|_| Error::new("Cannot hash password"))?;
+
+    let update = doc! {
+      "$set": { "password_hash": password_hash },
+      "$unset": { "password_reset": "" },
+      "$pull": {
+        "sessions": { "id": { "$ne": session.id.as_str() } },
+        "access_token": { "family": { "$ne": session.id.as_str() } },
+        "refresh_token": { "family": { "$ne": session.id.as_str() } },
+      },
+    };
+    if users.update_one(doc! { "_id": user.id.unwrap() }, update, None).await.is_err() {
+      return Err(Error::new("Cannot write to database"));
+    }
+
+    invalidate_user(pubsub, &user.id.unwrap()).await;
+    for other in user.sessions.iter().filter(|s| s.id != session.id) {
+      publish_session_revoked(pubsub, other.id.as_str()).await;
+    }
+    Ok(true)
+  }
+
+  // The new address has to be verified again. A pending reset was mailed to the old address and is dropped.
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn change_email(
+    &self,
+    ctx: &Context<'_>,
+    current_password: String,
+    #[graphql(validator(email, max_length = 64))] new_email: String,
+  ) -> Result<User> {
+    let user = current_user(ctx).await?;
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let mailer = ctx.data::<Mailer>().unwrap();
+    let info = ctx.data_opt::<RequestInfo>().cloned().unwrap_or_default();
+
+    let account = user.id.unwrap().to_hex();
+    let attempts = reserve_login(pubsub, account.as_str(), info.ip.as_deref()).await?;
+    if verify_password(user.password_hash.clone(), current_password).await.is_err() {
+      return Err(Error::new("The current password is wrong"));
+    }
+    attempts.succeeded(pubsub, account.as_str()).await;
+    ensure_available(users, None, Some(new_email.as_str())).await?;
+    let update = doc! {
+      "$set": { "email_address": new_email, "email_verified": false },
+      "$unset": { "password_reset": "" },
+    };
+    let entity = update_profile(users, &user.id.unwrap(), update).await?;
+    invalidate_user(pubsub, &user.id.unwrap()).await;
+
+    if let Err(err) = send_verification_mail(mailer, &entity).await {
+      log::warn!("Cannot send verification mail: {}", err);
+    }
+    Ok(User::from(entity))
+  }
+
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn change_username(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(validator(min_length = 4, max_length = 64))] new_name: String,
+  ) -> Result<User> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    ensure_available(users, Some(new_name.as_str()), None).await?;
+    let entity = update_profile(users, &user.id.unwrap(), doc! { "$set": { "name": new_name } }).await?;
+    invalidate_user(pubsub, &user.id.unwrap()).await;
+    Ok(User::from(entity))
+  }
+
+  // Start enrolling an authenticator, two-factor authentication is enabled by `confirm_totp`
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn enroll_totp(&self, ctx: &Context<'_>) -> Result<TotpEnrollment> {
+    let user = current_user(ctx).await?;
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+
+    if user.totp.as_ref().map_or(false, |t| t.enabled) {
+      return Err(Error::new("Two-factor authentication is already enabled"));
+    }
+    let secret = generate_secret();
+    let totp = TotpEntity::new(encrypt_secret(secret.as_str()));
+    let update = doc! { "$set": { "totp": mongodb::bson::to_bson(&totp)? }};
+    if users.update_one(doc! { "_id": user.id.unwrap() }, update, None).await.is_err() {
+      return Err(Error::new("Cannot write to database"));
+    }
+
+    Ok(TotpEnrollment {
+      uri: otpauth_uri(secret.as_str(), user.name.as_str()),
+      secret,
+    })
+  }
+
+  // Enable two-factor authentication with a first code. Returns the one-time recovery codes.
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn confirm_totp(&self, ctx: &Context<'_>, code: String) -> Result<Vec<String>> {
+    let user = current_user(ctx).await?;
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+
+    let totp = match user.totp {
+      Some(totp) if !totp.enabled => totp,
+      Some(_) => return Err(Error::new("Two-factor authentication is already enabled")),
+      None => return Err(Error::new("Two-factor authentication has not been enrolled")),
+    };
+    let step = decrypt_secret(totp.secret.as_str())
+      .and_then(|secret| verify_code(secret.as_str(), code.trim()))
+      .ok_or_else(|| Error::new("The code is invalid"))?;
+
+    let (codes, hashes) = generate_recovery_codes();
+    let update = doc! { "$set": { "totp.enabled": true, "totp.last_step": step, "totp.recovery_codes": hashes }};
+    match users.update_one(doc! { "_id": user.id.unwrap(), "totp.enabled": false }, update, None).await {
+      Ok(result) if result.modified_count == 1 => Ok(codes),
+      Ok(_) => Err(Error::new("Two-factor authentication is already enabled")),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  // Requires a current TOTP or recovery code
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn disable_totp(&self, ctx: &Context<'_>, code: String) -> Result<bool> {
+    let user = current_user(ctx).await?;
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let info = ctx.data_opt::<RequestInfo>().cloned().unwrap_or_default();
+
+    let totp = match user.totp {
+      Some(totp) if totp.enabled => totp,
+      _ => return Err(Error::new("Two-factor authentication is not enabled")),
+    };
+    // Guesses count towards the same lockout as logins
+    let account = user.id.unwrap().to_hex();
+    let attempts = reserve_login(pubsub, account.as_str(), info.ip.as_deref()).await?;
+    let valid = check_second_factor(users, &user.id.unwrap(), &totp, code.as_str())
+      .await
+      .map_err(|_| Error::new("Cannot write to database"))?;
+    if !valid {
+      return Err(Error::new("The code is invalid"));
+    }
+    attempts.succeeded(pubsub, account.as_str()).await;
+
+    match users.update_one(doc! { "_id": user.id.unwrap() }, doc! { "$unset": { "totp": "" }}, None).await {
+      Ok(_) => Ok(true),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn resend_verification_email(&self, ctx: &Context<'_>) -> Result<bool> {
+    let user = current_user(ctx).await?;
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let mailer = ctx.data::<Mailer>().unwrap();
+
+    if user.email_verified {
+      return Err(Error::new("The email address has already been verified"));
+    }
+    if !acquire(pubsub, format!("verification_mail:{}", user.id.unwrap().to_hex()), 60).await {
+      return Err(Error::new("Please wait a minute before requesting another mail"));
+    }
+    match send_verification_mail(mailer, &user).await {
+      Ok(_) => Ok(true),
+      Err(_) => Err(Error::new("The mail could not be sent")),
+    }
+  }
+
   pub async fn create_access_token(
     &self,
     ctx: &Context<'_>,
@@ -64,37 +447,171 @@ impl UserMutations {
           "$or": [{"name": args.name.clone() }, { "email_address": args.name.clone()}]
         };
     let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
-    if let Ok(result) = users
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let info = ctx.data_opt::<RequestInfo>().cloned().unwrap_or_default();
+
+    let result = users
       .find_one(filter.clone(), None)
       .await
-    {
-      if let Some(user) = result {
-        if let Ok(_) = verify_password(user.password_hash, args.password) {
-          let token = base64::encode(Uuid::new_v4().to_string());
-          let millis = Utc::now() + Duration::minutes(args.expire);
-          let expire = match args.expire {
-            0 => DateTime::parse_rfc3339_str("9999-12-31T23:59:59.00Z").unwrap(),
-            _ => DateTime::from_millis(millis.timestamp_millis())
-          };
+      .map_err(|_| Error::new("Cannot read from database"))?;
+    // Unknown names are counted too, so they cannot be told apart from existing accounts
+    let account = match result.as_ref() {
+      Some(user) => user.id.unwrap().to_hex(),
+      None => format!("name:{}", args.name.to_lowercase()),
+    };
+    // Locked logins are rejected before the password is hashed
+    let attempts = reserve_login(pubsub, account.as_str(), info.ip.as_deref()).await?;
 
-          if let Ok(result) = users.update_one(filter, doc! { "$push": {  "access_token": { "token": token.clone(), "expire": expire.clone() }}}, None).await {
-            let token = AccessToken::from(AccessTokenEntity::new(token, expire));
-            return match result.modified_count {
-              1 => {
-                users.update_one(doc! { "_id": user.id.unwrap() }, doc! { "$set": { "last_login": mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis() )}}, None).await.expect("Error updating timestamp");
-                Ok(token)
-              }
-              _ => Err(Error::new("Token could not be created"))
-            };
+    if let Some(user) = result {
+      if verify_password(user.password_hash.clone(), args.password.clone()).await.is_ok() {
+        if let Some(totp) = user.totp.as_ref().filter(|t| t.enabled) {
+          let valid = match args.otp.as_deref() {
+            Some(code) => check_second_factor(users, &user.id.unwrap(), totp, code)
+              .await
+              .map_err(|_| Error::new("Cannot write to database"))?,
+            None => false,
+          };
+          if !valid {
+            // Asking for the code is part of a normal login, only wrong codes count as failure
+            if args.otp.is_none() {
+              attempts.release(pubsub).await;
+            }
+            return Err(Error::new("A valid two-factor code is required").extend_with(|_, e| e.set("code", "TOTP_REQUIRED")));
           }
         }
+        attempts.succeeded(pubsub, account.as_str()).await;
+
+        // Hashes made with older parameters are replaced while the password is at hand,
+        // in the background so the login does not wait for a second hash
+        if needs_rehash(user.password_hash.as_str()) {
+          let users = users.clone();
+          let (id, previous) = (user.id.unwrap(), user.password_hash.clone());
+          let password = args.password;
+          tokio::spawn(async move {
+            if let Ok(password_hash) = hash_password(password).await {
+              let _ = users
+                .update_one(
+                  doc! { "_id": id, "password_hash": previous },
+                  doc! { "$set": { "password_hash": password_hash }},
+                  None,
+                )
+                .await;
+            }
+          });
+        }
+
+        // Every login starts a new session with its own token family
+        let expire = args.expire.unwrap_or(*ACCESS_TOKEN_TTL);
+
+        return match create_session(users, &user, &info, expire).await {
+          Ok(tokens) => {
+            let _ = users.update_one(doc! { "_id": user.id.unwrap() }, doc! { "$set": { "last_login": DateTime::from_millis(Utc::now().timestamp_millis() )}}, None).await;
+            Ok(AccessToken::from(tokens))
+          }
+          Err(_) => Err(Error::new("Token could not be created"))
+        };
       }
-    };
+    }
 
     Err(Error::new(
       "A access token could not be created.".to_string(),
     ))
   }
+
+  // Exchange a refresh token for a new access and refresh token
+  pub async fn refresh_access_token(&self, ctx: &Context<'_>, refresh_token: String) -> Result<AccessToken> {
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    match refresh_tokens(users, pubsub, refresh_token.as_str()).await {
+      Ok(tokens) => Ok(AccessToken::from(tokens)),
+      Err(err) => Err(Error::new(err.to_string())),
+    }
+  }
+
+  // Revoke the session of the current request
+  #[graphql(guard = "AuthGuard")]
+  pub async fn logout(&self, ctx: &Context<'_>) -> Result<bool> {
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+    let session = ctx
+      .data::<CurrentSession>()
+      .map_err(|_| Error::new("The request is not authenticated by a session"))?;
+
+    match revoke_family(users, pubsub, &session.user, session.id.as_str()).await {
+      Ok(_) => Ok(true),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn revoke_session(&self, ctx: &Context<'_>, id: ID) -> Result<bool> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    match revoke_family(users, pubsub, &user.id.unwrap(), id.as_str()).await {
+      Ok(true) => Ok(true),
+      Ok(false) => Err(Error::new("Session not found").extend_with(|_, e| e.set("code", "NOT_FOUND"))),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  // Revoke every session of the current user, including the current one. API tokens are kept.
+  // Returns the number of revoked sessions.
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn revoke_all_sessions(&self, ctx: &Context<'_>) -> Result<usize> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    // The document before the update holds exactly the sessions that were removed
+    let update = doc! { "$set": { "access_token": [], "refresh_token": [], "sessions": [] }};
+    let options = FindOneAndUpdateOptions::builder()
+      .projection(doc! { "password_hash": 0 })
+      .return_document(ReturnDocument::Before)
+      .build();
+    let previous = match users.find_one_and_update(doc! { "_id": user.id.unwrap() }, update, options).await {
+      Ok(Some(previous)) => previous,
+      Ok(None) => return Err(Error::new("User not found").extend_with(|_, e| e.set("code", "NOT_FOUND"))),
+      Err(_) => return Err(Error::new("Cannot write to database")),
+    };
+    invalidate_user(pubsub, &user.id.unwrap()).await;
+    for session in previous.sessions.iter() {
+      publish_session_revoked(pubsub, session.id.as_str()).await;
+    }
+    Ok(previous.sessions.len())
+  }
+
+  // Personal API tokens are created from a logged in session, so no password is needed
+  #[graphql(guard = "AuthGuard.and(SessionGuard).and(VerifiedGuard)")]
+  pub async fn create_api_token(&self, ctx: &Context<'_>, args: CreateApiTokenInput) -> Result<CreatedApiToken> {
+    let user = ctx.data::<UserEntity>().unwrap();
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+
+    match create_api_token(users, &user.id.unwrap(), args.name, args.scopes, args.vaults, args.expire).await {
+      Ok((token, entity)) => Ok(CreatedApiToken {
+        token,
+        api_token: ApiToken::from(entity),
+      }),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
+
+  #[graphql(guard = "AuthGuard.and(SessionGuard)")]
+  pub async fn revoke_api_token(&self, ctx: &Context<'_>, id: ID) -> Result<bool> {
+    let user = current_user(ctx).await?;
+    let users = ctx.data::<ModelFor<UserEntity>>().unwrap();
+    let pubsub = ctx.data::<PubSub>().unwrap();
+
+    if !user.api_token.iter().any(|t| t.id == id.as_str()) {
+      return Err(Error::new("API token not found").extend_with(|_, e| e.set("code", "NOT_FOUND")));
+    }
+    match revoke_family(users, pubsub, &user.id.unwrap(), id.as_str()).await {
+      Ok(_) => Ok(true),
+      Err(_) => Err(Error::new("Cannot write to database")),
+    }
+  }
 }
 
 /*#[Subscription]
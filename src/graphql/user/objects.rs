@@ -1,23 +1,93 @@
+use crate::auth::IssuedTokens;
 use crate::graphql::FromOid;
-use crate::models::user::{AccessTokenEntity, UserEntity};
+use crate::graphql::scopes::Scope;
+use crate::models::user::{ApiTokenEntity, SessionEntity, UserEntity};
 use async_graphql::{SimpleObject, ID};
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 
 #[derive(SimpleObject)]
 pub struct AccessToken {
     pub token: String,
     pub expire: i64,
+    pub refresh_token: String,
+    pub refresh_expire: i64,
 }
 
-impl From<AccessTokenEntity> for AccessToken {
-    fn from(t: AccessTokenEntity) -> Self {
+impl From<IssuedTokens> for AccessToken {
+    fn from(t: IssuedTokens) -> Self {
         Self {
-            token: t.token,
-            expire: t.expire.timestamp_millis(),
+            token: t.access_token,
+            expire: t.access_expire.timestamp_millis(),
+            refresh_token: t.refresh_token,
+            refresh_expire: t.refresh_expire.timestamp_millis(),
         }
     }
 }
 
+#[derive(SimpleObject)]
+pub struct Session {
+    pub id: ID,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub when_created: i64,
+    pub last_used: i64,
+    // Whether this is the session of the request
+    pub current: bool,
+}
+
+impl Session {
+    pub fn from_entity(e: SessionEntity, current: Option<&str>) -> Self {
+        Session {
+            current: current == Some(e.id.as_str()),
+            id: ID::from(e.id),
+            user_agent: e.user_agent,
+            ip: e.ip,
+            when_created: e.when_created.timestamp_millis(),
+            last_used: e.last_used.timestamp_millis(),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct ApiToken {
+    pub id: ID,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub vaults: Option<Vec<String>>,
+    pub expire: Option<i64>,
+    pub when_created: i64,
+    pub last_used: Option<i64>,
+}
+
+impl From<ApiTokenEntity> for ApiToken {
+    fn from(e: ApiTokenEntity) -> Self {
+        ApiToken {
+            id: ID::from(e.id),
+            name: e.name,
+            scopes: e.scopes,
+            vaults: e.vaults,
+            expire: e.expire.map(|d| d.timestamp_millis()),
+            when_created: e.when_created.timestamp_millis(),
+            last_used: e.last_used.map(|d| d.timestamp_millis()),
+        }
+    }
+}
+
+// Returned once on creation, the token itself cannot be read again
+#[derive(SimpleObject)]
+pub struct CreatedApiToken {
+    pub token: String,
+    pub api_token: ApiToken,
+}
+
+// Add the secret to an authenticator app, or scan the uri as QR code
+#[derive(SimpleObject)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
 #[derive(SimpleObject, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: ID,
@@ -29,6 +99,21 @@ pub struct User {
     pub last_login: i64,
 }
 
+impl User {
+    // Stands in for authors whose account has been removed
+    pub fn placeholder(id: ObjectId) -> Self {
+        User {
+            id: ID::from_object_id(id),
+            name: "Deleted user".to_owned(),
+            email_address: String::new(),
+            email_verified: false,
+            roles: vec![],
+            when_created: 0,
+            last_login: 0,
+        }
+    }
+}
+
 impl From<UserEntity> for User {
     fn from(e: UserEntity) -> Self {
         User {
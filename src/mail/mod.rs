@@ -0,0 +1,115 @@
+use chrono::Utc;
+use lazy_static::lazy_static;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::env::var;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+lazy_static! {
+    // Base url of the frontend, links in mails point to it
+    pub static ref APP_URL: String = var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_owned());
+    static ref MAIL_FROM: String = var("MAIL_FROM").unwrap_or_else(|_| "no-reply@localhost".to_owned());
+}
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    pub fn new(to: &str, subject: &str, body: String) -> Self {
+        Self {
+            to: to.to_owned(),
+            subject: subject.to_owned(),
+            body,
+        }
+    }
+
+    fn message(&self) -> anyhow::Result<Message> {
+        Ok(Message::builder()
+            .from(MAIL_FROM.parse::<Mailbox>()?)
+            .to(self.to.parse::<Mailbox>()?)
+            .subject(self.subject.as_str())
+            .body(self.body.clone())?)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, mail: &Mail) -> anyhow::Result<()>;
+}
+
+pub type Mailer = Arc<dyn MailTransport>;
+
+// Delivers mails to a SMTP server, e.g. `smtp://localhost:1025` for a local sink
+pub struct SmtpTransport {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send(&self, mail: &Mail) -> anyhow::Result<()> {
+        self.transport.send(mail.message()?).await?;
+        Ok(())
+    }
+}
+
+// Writes every mail as `.eml` file into a directory
+pub struct FileTransport {
+    dir: PathBuf,
+}
+
+impl FileTransport {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait::async_trait]
+impl MailTransport for FileTransport {
+    async fn send(&self, mail: &Mail) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self
+            .dir
+            .join(format!("{}-{}.eml", Utc::now().timestamp_millis(), Uuid::new_v4()));
+        tokio::fs::write(path, mail.message()?.formatted()).await?;
+        Ok(())
+    }
+}
+
+// Only logs mails, the default for development
+pub struct LogTransport;
+
+#[async_trait::async_trait]
+impl MailTransport for LogTransport {
+    async fn send(&self, mail: &Mail) -> anyhow::Result<()> {
+        log::info!("Mail to {}: {}\n{}", mail.to, mail.subject, mail.body);
+        Ok(())
+    }
+}
+
+// Selected by MAIL_TRANSPORT, one of `smtp` (with SMTP_URL), `file` (with MAIL_DIR) or `log`
+pub fn build_mailer() -> Mailer {
+    match var("MAIL_TRANSPORT").as_deref() {
+        Ok("smtp") => {
+            let url = var("SMTP_URL").expect("SMTP_URL not set in environment");
+            Arc::new(SmtpTransport::new(url.as_str()).expect("Invalid SMTP_URL"))
+        }
+        Ok("file") => Arc::new(FileTransport::new(PathBuf::from(
+            var("MAIL_DIR").unwrap_or_else(|_| "./mails".to_owned()),
+        ))),
+        Ok("log") | Err(_) => Arc::new(LogTransport),
+        Ok(transport) => panic!("Unknown MAIL_TRANSPORT '{}', use 'smtp', 'file' or 'log'", transport),
+    }
+}
@@ -1,17 +1,23 @@
 #![feature(iterator_try_collect)]
 
+mod auth;
+mod blob;
 mod graphql;
+mod mail;
+mod mentions;
 mod models;
 mod password;
 mod routes;
+mod search;
 mod connections;
 
 use crate::graphql::build_schema;
 use crate::graphql::roles::Role;
+use crate::graphql::scopes::Scope;
 use crate::models::model::ModelFor;
 
 use actix_web::{guard, web, web::Data, App, HttpServer};
-use routes::{gql::*, health::*};
+use routes::{blob::*, gql::*, health::*, oidc::*};
 use std::sync::{Arc, Mutex};
 use sysinfo::{RefreshKind, SystemExt};
 use std::env::var;
@@ -38,16 +44,27 @@ async fn main() -> std::io::Result<()> {
 
     println!("{}", format!("Playground IDE: http://localhost:{}", port));
 
+    // Signed tokens are used for email verification in any token mode
+    auth::signed::ensure_signing_key();
+    auth::hash::ensure_hash_key();
+    auth::totp::ensure_totp_key();
+    password::policy::ensure_password_policy();
+    auth::oidc::ensure_oidc_config();
+
     let mongo_database = build_database_connection(&MONGO_URL).await.expect("Cannot connect to mongodb");
     let pubsub = build_pubsub_client(&REDIS_URL).await.expect("Cannot connect to redis");
 
-    let schema = build_schema(mongo_database.clone(), pubsub).await;
+    // Migrations and indexes run here, a failure is reported instead of panicking
+    let schema = build_schema(mongo_database.clone(), pubsub.clone())
+        .await
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("Cannot build schema: {:#}", err)))?;
 
     HttpServer::new(move || {
         App::new()
             .app_data(Data::new(schema.clone()))
             .app_data(Data::new(sys.clone()))
             .app_data(Data::new(mongo_database.clone()))
+            .app_data(Data::new(pubsub.clone()))
             // Get/Post to /graphql (Get guarded with custom guard to look for ?query=
             .service(graphql_request)
             .service(graphql_query)
@@ -61,6 +78,17 @@ async fn main() -> std::io::Result<()> {
             // Playground endpoint
             .service(graphql_playground)
             .service(health)
+            // Attachment uploads and downloads
+            .service(
+                web::resource("/blobs")
+                    .app_data(web::PayloadConfig::new(*blob::BLOB_MAX_SIZE))
+                    .route(web::post().to(upload_blob)),
+            )
+            .service(download_thumbnail)
+            .service(download_blob)
+            // Login with an identity provider
+            .service(oidc_login)
+            .service(oidc_callback)
     })
     .bind(format!("{}:{}", bind,port))?
     .run()
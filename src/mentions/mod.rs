@@ -0,0 +1,52 @@
+// Extract the distinct user names mentioned as `@name` in a text
+pub fn parse_mentions(text: &str) -> Vec<String> {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+
+    let mut mentions: Vec<String> = vec![];
+    let mut previous: Option<char> = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        // An `@` only starts a mention at the beginning of a word, not inside email addresses
+        if c == '@' && !previous.map_or(false, is_name_char) {
+            let start = index + c.len_utf8();
+            let mut end = start;
+            while let Some(&(i, n)) = chars.peek() {
+                if !is_name_char(n) {
+                    break;
+                }
+                end = i + n.len_utf8();
+                chars.next();
+            }
+            // Trailing dots are punctuation, not part of the name
+            let name = text[start..end].trim_end_matches('.');
+            if !name.is_empty() && !mentions.iter().any(|m| m == name) {
+                mentions.push(name.to_string());
+            }
+        }
+        previous = Some(c);
+    }
+
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_mentions;
+
+    #[test]
+    fn finds_distinct_mentions() {
+        assert_eq!(parse_mentions("hi @alice and @bob."), vec!["alice", "bob"]);
+        assert_eq!(parse_mentions("@alice @alice"), vec!["alice"]);
+        assert_eq!(parse_mentions("(@carol)"), vec!["carol"]);
+        assert_eq!(parse_mentions("@dave.smith's idea"), vec!["dave.smith"]);
+    }
+
+    #[test]
+    fn ignores_email_addresses_and_lone_signs() {
+        assert!(parse_mentions("mail bob@example.com").is_empty());
+        assert!(parse_mentions("@ alone").is_empty());
+        assert!(parse_mentions("@.").is_empty());
+        assert!(parse_mentions("").is_empty());
+    }
+}
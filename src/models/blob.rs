@@ -0,0 +1,33 @@
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+// Metadata of an uploaded file, the content itself lives in the blob directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobEntity {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub owner: ObjectId,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub has_thumbnail: bool,
+
+    pub when_created: DateTime,
+}
+
+impl BlobEntity {
+    pub fn new(owner: ObjectId, file_name: String, mime_type: String, size: i64) -> Self {
+        Self {
+            id: Some(ObjectId::new()),
+            owner,
+            file_name,
+            mime_type,
+            size,
+            has_thumbnail: false,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+        }
+    }
+}
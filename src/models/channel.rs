@@ -1,8 +1,51 @@
+use async_graphql::Enum;
 use chrono::Utc;
 use mongodb::bson::oid::ObjectId;
 use mongodb::bson::DateTime;
 use serde::{Deserialize, Serialize};
 
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ChannelKind {
+    Channel,
+    // Direct conversation between a fixed set of users
+    Direct,
+}
+
+impl ChannelKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelKind::Channel => "Channel",
+            ChannelKind::Direct => "Direct",
+        }
+    }
+}
+
+impl Default for ChannelKind {
+    fn default() -> Self {
+        ChannelKind::Channel
+    }
+}
+
+// A mute or ban of a user, optionally limited in time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelRestrictionEntity {
+    pub user: ObjectId,
+    pub until: Option<DateTime>,
+}
+
+impl ChannelRestrictionEntity {
+    pub fn new(user: ObjectId, until: Option<DateTime>) -> Self {
+        Self { user, until }
+    }
+
+    pub fn is_active(&self) -> bool {
+        match self.until {
+            Some(until) => until.timestamp_millis() > Utc::now().timestamp_millis(),
+            None => true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelEntity {
     #[serde(rename = "_id")]
@@ -11,21 +54,140 @@ pub struct ChannelEntity {
     pub name: String,
     pub description: String,
     pub public: bool,
+    #[serde(default)]
+    pub kind: ChannelKind,
+    #[serde(default)]
+    pub archived: bool,
+
+    #[serde(default)]
+    pub owner: Option<ObjectId>,
+    #[serde(default)]
+    pub members: Vec<ObjectId>,
+    // Sorted participants of a direct conversation, unique so each set of users has a single conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direct_key: Option<String>,
+
+    #[serde(default)]
+    pub muted: Vec<ChannelRestrictionEntity>,
+    #[serde(default)]
+    pub banned: Vec<ChannelRestrictionEntity>,
+    // Minimum seconds between two messages of the same user, 0 disables slow mode
+    #[serde(default)]
+    pub slow_mode: i64,
+
     pub when_created: DateTime,
     pub last_publish: DateTime,
     pub last_subscribe: DateTime,
 }
 
 impl ChannelEntity {
-    pub fn new(name: String, description: String, public: bool) -> Self {
+    pub fn new(name: String, description: String, public: bool, owner: ObjectId) -> Self {
         Self {
             id: Some(ObjectId::new()),
             name,
             description,
             public,
+            kind: ChannelKind::Channel,
+            archived: false,
+            owner: Some(owner),
+            members: vec![owner],
+            direct_key: None,
+            muted: vec![],
+            banned: vec![],
+            slow_mode: 0,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+            last_publish: DateTime::from_millis(Utc::now().timestamp_millis()),
+            last_subscribe: DateTime::from_millis(Utc::now().timestamp_millis()),
+        }
+    }
+
+    pub fn direct(members: Vec<ObjectId>) -> Self {
+        Self {
+            id: Some(ObjectId::new()),
+            name: String::new(),
+            description: String::new(),
+            public: false,
+            kind: ChannelKind::Direct,
+            archived: false,
+            owner: None,
+            direct_key: Some(Self::direct_key(&members)),
+            members,
+            muted: vec![],
+            banned: vec![],
+            slow_mode: 0,
             when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
             last_publish: DateTime::from_millis(Utc::now().timestamp_millis()),
             last_subscribe: DateTime::from_millis(Utc::now().timestamp_millis()),
         }
     }
+
+    pub fn direct_key(members: &[ObjectId]) -> String {
+        let mut members = members.iter().map(|m| m.to_hex()).collect::<Vec<String>>();
+        members.sort();
+        members.dedup();
+        members.join(",")
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.kind == ChannelKind::Direct
+    }
+
+    pub fn is_owner(&self, user: &ObjectId) -> bool {
+        self.owner.as_ref() == Some(user)
+    }
+
+    pub fn is_member(&self, user: &ObjectId) -> bool {
+        self.members.contains(user)
+    }
+
+    pub fn is_muted(&self, user: &ObjectId) -> bool {
+        self.muted.iter().any(|r| &r.user == user && r.is_active())
+    }
+
+    pub fn is_banned(&self, user: &ObjectId) -> bool {
+        self.banned.iter().any(|r| &r.user == user && r.is_active())
+    }
+
+    // Public channels are open to everyone, private ones only to their members. Banned users are locked out of both.
+    pub fn can_read(&self, user: &ObjectId) -> bool {
+        !self.is_banned(user) && (self.public || self.is_member(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_minutes(minutes: i64) -> Option<DateTime> {
+        Some(DateTime::from_millis((Utc::now() + chrono::Duration::minutes(minutes)).timestamp_millis()))
+    }
+
+    #[test]
+    fn restrictions_expire() {
+        let user = ObjectId::new();
+        assert!(ChannelRestrictionEntity::new(user, None).is_active());
+        assert!(ChannelRestrictionEntity::new(user, in_minutes(5)).is_active());
+        assert!(!ChannelRestrictionEntity::new(user, in_minutes(-5)).is_active());
+    }
+
+    #[test]
+    fn expired_bans_do_not_lock_out() {
+        let (owner, user) = (ObjectId::new(), ObjectId::new());
+        let mut channel = ChannelEntity::new("general".to_owned(), String::new(), true, owner);
+        channel.banned.push(ChannelRestrictionEntity::new(user, in_minutes(-1)));
+        assert!(!channel.is_banned(&user));
+        assert!(channel.can_read(&user));
+
+        channel.banned.push(ChannelRestrictionEntity::new(user, in_minutes(60)));
+        assert!(channel.is_banned(&user));
+        assert!(!channel.can_read(&user));
+    }
+
+    #[test]
+    fn direct_key_ignores_order_and_duplicates() {
+        let (a, b, c) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        assert_eq!(ChannelEntity::direct_key(&[a, b, c]), ChannelEntity::direct_key(&[c, a, b]));
+        assert_eq!(ChannelEntity::direct_key(&[a, b, a]), ChannelEntity::direct_key(&[b, a]));
+        assert_ne!(ChannelEntity::direct_key(&[a, b]), ChannelEntity::direct_key(&[a, c]));
+    }
 }
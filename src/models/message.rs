@@ -0,0 +1,90 @@
+use crate::models::blob::BlobEntity;
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionEntity {
+    pub emoji: String,
+    pub users: Vec<ObjectId>,
+}
+
+// Copy of the blob metadata at the time it got attached
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentEntity {
+    pub blob: ObjectId,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub has_thumbnail: bool,
+}
+
+impl From<BlobEntity> for AttachmentEntity {
+    fn from(e: BlobEntity) -> Self {
+        Self {
+            blob: e.id.unwrap(),
+            file_name: e.file_name,
+            mime_type: e.mime_type,
+            size: e.size,
+            has_thumbnail: e.has_thumbnail,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelMessageEntity {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub channel: ObjectId,
+    pub author: ObjectId,
+    pub message: String,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentEntity>,
+
+    // Replies point to the message that started the thread
+    #[serde(default)]
+    pub parent: Option<ObjectId>,
+    #[serde(default)]
+    pub reply_count: i64,
+    #[serde(default)]
+    pub last_reply: Option<DateTime>,
+
+    #[serde(default)]
+    pub reactions: Vec<ReactionEntity>,
+
+    pub when_created: DateTime,
+    #[serde(default)]
+    pub when_edited: Option<DateTime>,
+}
+
+impl ChannelMessageEntity {
+    pub fn new(
+        channel: ObjectId,
+        author: ObjectId,
+        message: String,
+        attachments: Vec<AttachmentEntity>,
+        parent: Option<ObjectId>,
+    ) -> Self {
+        Self {
+            id: Some(ObjectId::new()),
+            channel,
+            author,
+            message,
+            attachments,
+            parent,
+            reply_count: 0,
+            last_reply: None,
+            reactions: vec![],
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+            when_edited: None,
+        }
+    }
+
+    pub fn has_reacted(&self, user: &ObjectId, emoji: &str) -> bool {
+        self.reactions
+            .iter()
+            .any(|r| r.emoji == emoji && r.users.contains(user))
+    }
+}
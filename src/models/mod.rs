@@ -1,3 +1,8 @@
+pub mod blob;
 pub mod channel;
+pub mod message;
 pub mod model;
+pub mod moderation;
+pub mod notification;
+pub mod read;
 pub mod user;
@@ -1,9 +1,9 @@
 use std::borrow::Borrow;
 
 use mongodb::bson::Document;
-use mongodb::options::{DeleteOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertOneOptions, UpdateModifications, UpdateOptions};
-use mongodb::results::{DeleteResult, InsertOneResult, UpdateResult};
-use mongodb::{error::Result, Collection, Cursor, Database};
+use mongodb::options::{CountOptions, CreateIndexOptions, DeleteOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertOneOptions, UpdateModifications, UpdateOptions};
+use mongodb::results::{CreateIndexResult, DeleteResult, InsertOneResult, UpdateResult};
+use mongodb::{error::Result, Collection, Cursor, Database, IndexModel};
 use serde::de::DeserializeOwned;
 use std::sync::Arc;
 use serde::Serialize;
@@ -42,6 +42,15 @@ impl<T> ModelFor<T>
     self._collection.find(filter, options).await
   }
 
+  #[allow(dead_code)]
+  pub async fn count_documents(
+    &self,
+    filter: impl Into<Option<Document>>,
+    options: impl Into<Option<CountOptions>>,
+  ) -> Result<u64> {
+    self._collection.count_documents(filter, options).await
+  }
+
   #[allow(dead_code)]
   pub async fn find_one_and_update(
     &self, filter: Document, update: impl Into<UpdateModifications>, options: impl Into<Option<FindOneAndUpdateOptions>>,
@@ -68,6 +77,16 @@ impl<T> ModelFor<T>
     self._collection.update_one(filter, update, options).await
   }
 
+  #[allow(dead_code)]
+  pub async fn update_many(
+    &self,
+    filter: Document,
+    update: impl Into<UpdateModifications>,
+    options: impl Into<Option<UpdateOptions>>,
+  ) -> Result<UpdateResult> {
+    self._collection.update_many(filter, update, options).await
+  }
+
   #[allow(dead_code)]
   pub async fn delete_one(
     &self,
@@ -76,4 +95,22 @@ impl<T> ModelFor<T>
   ) -> Result<DeleteResult> {
     self._collection.delete_one(filter, options).await
   }
+
+  #[allow(dead_code)]
+  pub async fn delete_many(
+    &self,
+    filter: Document,
+    options: impl Into<Option<DeleteOptions>>,
+  ) -> Result<DeleteResult> {
+    self._collection.delete_many(filter, options).await
+  }
+
+  #[allow(dead_code)]
+  pub async fn create_index(
+    &self,
+    index: IndexModel,
+    options: impl Into<Option<CreateIndexOptions>>,
+  ) -> Result<CreateIndexResult> {
+    self._collection.create_index(index, options).await
+  }
 }
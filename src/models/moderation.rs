@@ -0,0 +1,48 @@
+use async_graphql::Enum;
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ModerationAction {
+    Mute,
+    Unmute,
+    Ban,
+    Unban,
+    SlowMode,
+}
+
+// Record of a moderation action taken in a channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationEntity {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub channel: ObjectId,
+    pub actor: ObjectId,
+    pub action: ModerationAction,
+
+    pub target: Option<ObjectId>,
+    pub until: Option<DateTime>,
+    pub slow_mode: Option<i64>,
+    pub reason: Option<String>,
+
+    pub when_created: DateTime,
+}
+
+impl ModerationEntity {
+    pub fn new(channel: ObjectId, actor: ObjectId, action: ModerationAction) -> Self {
+        Self {
+            id: Some(ObjectId::new()),
+            channel,
+            actor,
+            action,
+            target: None,
+            until: None,
+            slow_mode: None,
+            reason: None,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+        }
+    }
+}
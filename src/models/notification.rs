@@ -0,0 +1,52 @@
+use async_graphql::Enum;
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum NotificationKind {
+    Mention,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationEntity {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub user: ObjectId,
+    pub kind: NotificationKind,
+
+    pub from: ObjectId,
+    pub from_name: String,
+    pub channel: Option<ObjectId>,
+    pub message: Option<ObjectId>,
+    pub excerpt: String,
+
+    pub read: bool,
+    pub when_created: DateTime,
+}
+
+impl NotificationEntity {
+    pub fn mention(
+        user: ObjectId,
+        from: ObjectId,
+        from_name: String,
+        channel: Option<ObjectId>,
+        message: Option<ObjectId>,
+        excerpt: String,
+    ) -> Self {
+        Self {
+            id: Some(ObjectId::new()),
+            user,
+            kind: NotificationKind::Mention,
+            from,
+            from_name,
+            channel,
+            message,
+            excerpt,
+            read: false,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+        }
+    }
+}
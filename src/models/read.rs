@@ -0,0 +1,15 @@
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+// Position up to which a user has read a channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelReadEntity {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub user: ObjectId,
+    pub channel: ObjectId,
+    pub last_read: ObjectId,
+    pub when_updated: DateTime,
+}
@@ -1,5 +1,5 @@
-use crate::password::hash_password;
 use crate::Role;
+use crate::Scope;
 use chrono::Utc;
 use mongodb::bson::oid::ObjectId;
 use mongodb::bson::DateTime;
@@ -7,15 +7,157 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccessTokenEntity {
+    // Hash of the token, plain for tokens created before hashing was introduced
     pub token: String,
+    #[serde(default)]
+    pub hashed: bool,
     pub expire: DateTime,
+
+    // All tokens issued from one login share a family
+    #[serde(default)]
+    pub family: Option<String>,
 }
 
 impl AccessTokenEntity {
-    pub fn new(t: String, e: DateTime) -> Self {
+    pub fn new(t: String, e: DateTime, family: String) -> Self {
         Self {
             token: t,
+            hashed: true,
             expire: e,
+            family: Some(family),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenEntity {
+    pub token: String,
+    #[serde(default)]
+    pub hashed: bool,
+    pub expire: DateTime,
+    pub family: String,
+
+    // Refresh tokens can only be exchanged once, a second use revokes the family
+    pub used: bool,
+    pub when_created: DateTime,
+}
+
+impl RefreshTokenEntity {
+    pub fn new(token: String, expire: DateTime, family: String) -> Self {
+        Self {
+            token,
+            hashed: true,
+            expire,
+            family,
+            used: false,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+        }
+    }
+}
+
+// A login, identified by the family of its tokens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionEntity {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub when_created: DateTime,
+    pub last_used: DateTime,
+}
+
+impl SessionEntity {
+    pub fn new(id: String, user_agent: Option<String>, ip: Option<String>) -> Self {
+        let now = DateTime::from_millis(Utc::now().timestamp_millis());
+        Self {
+            id,
+            user_agent,
+            ip,
+            when_created: now,
+            last_used: now,
+        }
+    }
+}
+
+// Personal token for integrations, limited to its scopes and optionally to some vaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenEntity {
+    pub id: String,
+    pub name: String,
+    // Hash of the token
+    pub token: String,
+    pub scopes: Vec<Scope>,
+    pub vaults: Option<Vec<String>>,
+    pub expire: Option<DateTime>,
+    pub when_created: DateTime,
+    pub last_used: Option<DateTime>,
+}
+
+impl ApiTokenEntity {
+    pub fn new(name: String, token: String, scopes: Vec<Scope>, vaults: Option<Vec<String>>, expire: Option<DateTime>) -> Self {
+        Self {
+            id: ObjectId::new().to_hex(),
+            name,
+            token,
+            scopes,
+            vaults,
+            expire,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+            last_used: None,
+        }
+    }
+}
+
+// Two-factor authentication, the secret is encrypted and recovery codes are hashed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpEntity {
+    pub secret: String,
+    // Set once the first code has been confirmed
+    pub enabled: bool,
+    pub recovery_codes: Vec<String>,
+    // Time step of the last accepted code, codes cannot be replayed
+    pub last_step: i64,
+    pub when_created: DateTime,
+}
+
+impl TotpEntity {
+    pub fn new(secret: String) -> Self {
+        Self {
+            secret,
+            enabled: false,
+            recovery_codes: vec![],
+            last_step: 0,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
+        }
+    }
+}
+
+// Pending password reset, the token is stored as hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordResetEntity {
+    pub token: String,
+    pub expire: DateTime,
+}
+
+impl PasswordResetEntity {
+    pub fn new(token: String, expire: DateTime) -> Self {
+        Self { token, expire }
+    }
+}
+
+// Account at an external identity provider, identified by issuer and subject of its ID tokens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityEntity {
+    pub issuer: String,
+    pub subject: String,
+    pub when_created: DateTime,
+}
+
+impl IdentityEntity {
+    pub fn new(issuer: String, subject: String) -> Self {
+        Self {
+            issuer,
+            subject,
+            when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
         }
     }
 }
@@ -35,23 +177,47 @@ pub struct UserEntity {
     pub password_hash: String,
 
     pub access_token: Vec<AccessTokenEntity>,
+    #[serde(default)]
+    pub refresh_token: Vec<RefreshTokenEntity>,
+    #[serde(default)]
+    pub sessions: Vec<SessionEntity>,
+    #[serde(default)]
+    pub api_token: Vec<ApiTokenEntity>,
+    #[serde(default)]
+    pub password_reset: Option<PasswordResetEntity>,
+    #[serde(default)]
+    pub totp: Option<TotpEntity>,
+    #[serde(default)]
+    pub identities: Vec<IdentityEntity>,
     pub when_created: DateTime,
     pub last_login: DateTime,
     pub last_access: DateTime,
 }
 
 impl UserEntity {
-    pub fn new(name: String, email: String, password: String) -> Self {
-        let password_hash = hash_password(password).unwrap();
+    pub fn new(name: String, email: String, password_hash: String) -> Self {
+        Self {
+            password_hash,
+            ..Self::without_password(name, email)
+        }
+    }
 
+    // Users of an identity provider cannot log in with a password until they set one
+    pub fn without_password(name: String, email: String) -> Self {
         Self {
             id: Some(ObjectId::new()),
             name,
             email_address: email,
-            email_verified: true,
-            password_hash,
+            email_verified: false,
+            password_hash: String::new(),
             roles: vec![],
             access_token: vec![],
+            refresh_token: vec![],
+            sessions: vec![],
+            api_token: vec![],
+            password_reset: None,
+            totp: None,
+            identities: vec![],
             when_created: DateTime::from_millis(Utc::now().timestamp_millis()),
             last_login: DateTime::from_millis(Utc::now().timestamp_millis()),
             last_access: DateTime::from_millis(Utc::now().timestamp_millis()),
@@ -1,7 +1,35 @@
+pub mod policy;
+
 use dryoc::pwhash::*;
 use dryoc::Error;
+use lazy_static::lazy_static;
+use std::env::var;
+
+lazy_static! {
+    // Argon2id iterations
+    static ref PASSWORD_HASH_OPSLIMIT: u64 = var("PASSWORD_HASH_OPSLIMIT")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(2)
+        .max(1);
+    // Argon2id memory in KiB, the same unit as `m=` in the stored hash
+    static ref PASSWORD_HASH_MEMLIMIT: usize = var("PASSWORD_HASH_MEMLIMIT")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(65536)
+        .max(8);
+}
+
+// Argon2 would hold up an async worker for the whole hash, so it runs on the blocking pool
+pub async fn hash_password(password: String) -> anyhow::Result<String> {
+    Ok(tokio::task::spawn_blocking(move || argon2_hash(password)).await??)
+}
 
-pub fn hash_password(password: String) -> Result<String, Error> {
+pub async fn verify_password(hash: String, password: String) -> anyhow::Result<()> {
+    Ok(tokio::task::spawn_blocking(move || argon2_verify(hash, password)).await??)
+}
+
+fn argon2_hash(password: String) -> Result<String, Error> {
     // Generate a random salt
     let mut salt = Salt::default();
     salt.resize(dryoc::constants::CRYPTO_PWHASH_SALTBYTES, 0);
@@ -11,13 +39,67 @@ pub fn hash_password(password: String) -> Result<String, Error> {
     let pwhash: VecPwHash = PwHash::hash_with_salt(
         &password.into_bytes(),
         salt,
-        Config::interactive().with_opslimit(1).with_memlimit(8192),
+        Config::interactive()
+            .with_opslimit(*PASSWORD_HASH_OPSLIMIT)
+            .with_memlimit(*PASSWORD_HASH_MEMLIMIT * 1024),
     )?;
 
+    // The encoded hash carries its parameters, `$argon2id$v=19$m=..,t=..,p=..$salt$hash`
     Ok(pwhash.to_string())
 }
 
-pub fn verify_password(hash: String, password: String) -> Result<(), Error> {
+fn argon2_verify(hash: String, password: String) -> Result<(), Error> {
     let pwhash = VecPwHash::from_string(hash.as_str())?;
     pwhash.verify(&password.into_bytes())
 }
+
+// Memory in KiB and iterations a hash was made with
+fn hash_params(hash: &str) -> Option<(usize, u64)> {
+    let mut parts = hash.split('$').skip(1);
+    if parts.next()? != "argon2id" {
+        return None;
+    }
+    let _version = parts.next()?;
+
+    let (mut memlimit, mut opslimit) = (None, None);
+    for param in parts.next()?.split(',') {
+        match param.split_once('=')? {
+            ("m", value) => memlimit = value.parse::<usize>().ok(),
+            ("t", value) => opslimit = value.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+    Some((memlimit?, opslimit?))
+}
+
+// Whether a hash was made with other parameters than the configured ones and should be replaced on the next login
+pub fn needs_rehash(hash: &str) -> bool {
+    hash_params(hash) != Some((*PASSWORD_HASH_MEMLIMIT, *PASSWORD_HASH_OPSLIMIT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_parameters_of_a_hash() {
+        let hash = "$argon2id$v=19$m=65536,t=2,p=1$c2FsdHNhbHRzYWx0$aGFzaA";
+        assert_eq!(hash_params(hash), Some((65536, 2)));
+        assert_eq!(hash_params("$argon2i$v=19$m=65536,t=2,p=1$c2FsdA$aGFzaA"), None);
+        assert_eq!(hash_params("$argon2id$v=19$p=1$c2FsdA$aGFzaA"), None);
+        assert_eq!(hash_params("not a hash"), None);
+    }
+
+    #[test]
+    fn fresh_hashes_need_no_rehash() {
+        let hash = argon2_hash("correct horse battery staple".to_owned()).unwrap();
+        assert!(!needs_rehash(hash.as_str()));
+        assert!(argon2_verify(hash, "correct horse battery staple".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn hashes_with_other_parameters_need_a_rehash() {
+        assert!(needs_rehash("$argon2id$v=19$m=4096,t=3,p=1$c2FsdHNhbHRzYWx0$aGFzaA"));
+        assert!(needs_rehash(""));
+    }
+}
@@ -0,0 +1,89 @@
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::env::var;
+use std::fmt::{Display, Formatter};
+
+lazy_static! {
+    static ref PASSWORD_MIN_LENGTH: usize = var("PASSWORD_MIN_LENGTH")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(10);
+    // Known breached or common passwords, one per line. Compared case insensitive.
+    static ref BREACHED_PASSWORDS: HashSet<String> = match var("PASSWORD_BREACHED_LIST") {
+        Ok(path) => std::fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("Cannot read PASSWORD_BREACHED_LIST '{}': {}", path, err))
+            .lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect(),
+        Err(_) => HashSet::new(),
+    };
+}
+
+// Load the breached password list on startup rather than on the first password to check
+pub fn ensure_password_policy() {
+    lazy_static::initialize(&BREACHED_PASSWORDS);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PasswordPolicyError {
+    TooShort(usize),
+    Breached,
+    ContainsIdentity,
+}
+
+impl Display for PasswordPolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordPolicyError::TooShort(min) => write!(f, "The password must have at least {} characters", min),
+            PasswordPolicyError::Breached => write!(f, "The password is known from data breaches, choose another one"),
+            PasswordPolicyError::ContainsIdentity => write!(f, "The password must not contain the user name or email address"),
+        }
+    }
+}
+
+impl std::error::Error for PasswordPolicyError {}
+
+// Check a new password against the policy, `identity` are the name and email address of the user
+pub fn check_password(password: &str, identity: &[&str]) -> Result<(), PasswordPolicyError> {
+    if password.chars().count() < *PASSWORD_MIN_LENGTH {
+        return Err(PasswordPolicyError::TooShort(*PASSWORD_MIN_LENGTH));
+    }
+
+    let lower = password.to_lowercase();
+    if BREACHED_PASSWORDS.contains(&lower) {
+        return Err(PasswordPolicyError::Breached);
+    }
+    if identity
+        .iter()
+        .map(|i| i.trim().to_lowercase())
+        .any(|i| i.len() >= 4 && lower.contains(i.as_str()))
+    {
+        return Err(PasswordPolicyError::ContainsIdentity);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_short_passwords() {
+        assert_eq!(check_password("short", &[]), Err(PasswordPolicyError::TooShort(10)));
+        assert_eq!(check_password("longenough", &[]), Ok(()));
+    }
+
+    #[test]
+    fn rejects_passwords_containing_the_identity() {
+        let identity = ["Alice", "alice@example.com"];
+        assert_eq!(check_password("my-ALICE-password", &identity), Err(PasswordPolicyError::ContainsIdentity));
+        assert_eq!(check_password("xalice@example.comx", &identity), Err(PasswordPolicyError::ContainsIdentity));
+        assert_eq!(check_password("an unrelated phrase", &identity), Ok(()));
+    }
+
+    #[test]
+    fn ignores_short_identities() {
+        assert_eq!(check_password("password-of-bob", &["bob"]), Ok(()));
+    }
+}
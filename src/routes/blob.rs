@@ -0,0 +1,328 @@
+use crate::auth::CurrentSession;
+use crate::blob::{blob_path, create_thumbnail, is_image, signed_blob_url, thumbnail_path, verify_blob_signature, BLOB_DIR, BLOB_MAX_SIZE};
+use crate::connections::PubSub;
+use crate::models::blob::BlobEntity;
+use crate::models::channel::ChannelEntity;
+use crate::models::message::ChannelMessageEntity;
+use crate::models::user::UserEntity;
+use crate::routes::gql::{get_auth_from_headers, get_session_from_token};
+use crate::Scope;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header;
+use actix_web::http::header::{Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue};
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use futures::stream::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct BlobResponse {
+    pub id: String,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+}
+
+impl From<BlobEntity> for BlobResponse {
+    fn from(e: BlobEntity) -> Self {
+        let id = e.id.unwrap();
+        BlobResponse {
+            url: signed_blob_url(&id, false),
+            thumbnail_url: match e.has_thumbnail {
+                true => Some(signed_blob_url(&id, true)),
+                false => None,
+            },
+            id: id.to_hex(),
+            file_name: e.file_name,
+            mime_type: e.mime_type,
+            size: e.size,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BlobQuery {
+    name: Option<String>,
+    // Signed url of a single blob, see `signed_blob_url`
+    signature: Option<String>,
+}
+
+// Blobs are authenticated like graphql requests by header, tokens in urls would end up in logs.
+// API tokens need the given scope.
+async fn authenticate(db: Arc<Database>, pubsub: &PubSub, req: &HttpRequest, scope: Scope) -> Option<UserEntity> {
+    let token = get_auth_from_headers(req.headers())?;
+    let (user, session) = get_session_from_token(db, pubsub, token).await?;
+    match has_scope(&session, scope) {
+        true => Some(user),
+        false => None,
+    }
+}
+
+fn has_scope(session: &CurrentSession, scope: Scope) -> bool {
+    session.scopes.as_ref().map_or(true, |scopes| scopes.contains(&scope))
+}
+
+// Owners can always access their blobs, everyone else only through a message in a channel they can read
+async fn can_access(db: &Database, blob: &BlobEntity, user: &UserEntity) -> Result<bool> {
+    let user_id = user.id.unwrap();
+    if blob.owner == user_id {
+        return Ok(true);
+    }
+
+    let messages: Vec<ChannelMessageEntity> = db
+        .collection::<ChannelMessageEntity>("channel_messages")
+        .find(doc! { "attachments.blob": blob.id.unwrap() }, None)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .try_collect()
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let channels = messages.iter().map(|m| m.channel).collect::<Vec<ObjectId>>();
+
+    let channels: Vec<ChannelEntity> = db
+        .collection::<ChannelEntity>("channel")
+        .find(doc! { "_id": { "$in": channels }}, None)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .try_collect()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(channels.iter().any(|c| c.can_read(&user_id)))
+}
+
+async fn find_blob(db: &Database, id: &str) -> Result<Option<BlobEntity>> {
+    let id = match ObjectId::from_str(id) {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    db.collection::<BlobEntity>("blobs")
+        .find_one(doc! { "_id": id }, None)
+        .await
+        .map_err(ErrorInternalServerError)
+}
+
+// Only keep the last path segment and a sane length of client provided file names
+fn sanitize_file_name(name: Option<String>) -> String {
+    let name = name.unwrap_or_default();
+    let name = name
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect::<String>();
+    match name.trim().is_empty() {
+        true => "file".to_owned(),
+        false => name,
+    }
+}
+
+// Upload the raw request body as a blob, the file name is passed as `?name=`.
+// Registered as resource in main, so the raised payload limit only applies here.
+pub async fn upload_blob(
+    db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
+    req: HttpRequest,
+    query: web::Query<BlobQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let db = db.into_inner();
+    let user = match authenticate(db.clone(), &pubsub, &req, Scope::ChannelPost).await {
+        Some(user) => user,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    if body.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("The upload is empty"));
+    }
+    if body.len() > *BLOB_MAX_SIZE {
+        return Ok(HttpResponse::PayloadTooLarge().finish());
+    }
+
+    let mime_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "application/octet-stream".to_owned());
+
+    let mut entity = BlobEntity::new(
+        user.id.unwrap(),
+        sanitize_file_name(query.into_inner().name),
+        mime_type,
+        body.len() as i64,
+    );
+    let id = entity.id.unwrap();
+
+    tokio::fs::create_dir_all(&*BLOB_DIR).await?;
+    tokio::fs::write(blob_path(&id), &body).await?;
+
+    if is_image(entity.mime_type.as_str()) {
+        // Decoding and scaling is cpu bound, keep it off the async workers
+        if let Ok(Some(thumbnail)) = web::block(move || create_thumbnail(&body)).await {
+            tokio::fs::write(thumbnail_path(&id), thumbnail).await?;
+            entity.has_thumbnail = true;
+        }
+    }
+
+    db.collection::<BlobEntity>("blobs")
+        .insert_one(&entity, None)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Created().json(BlobResponse::from(entity)))
+}
+
+async fn serve_blob(
+    db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
+    req: HttpRequest,
+    query: web::Query<BlobQuery>,
+    id: String,
+    thumbnail: bool,
+) -> Result<HttpResponse> {
+    let db = db.into_inner();
+    // A valid signature was issued to someone who could access the blob, anything else needs a token
+    let signed = match (query.signature.as_deref(), ObjectId::from_str(id.as_str())) {
+        (Some(signature), Ok(blob)) => verify_blob_signature(&blob, signature),
+        _ => false,
+    };
+    let user = match signed {
+        true => None,
+        false => match authenticate(db.clone(), &pubsub, &req, Scope::ChannelRead).await {
+            Some(user) => Some(user),
+            None => return Ok(HttpResponse::Unauthorized().finish()),
+        },
+    };
+
+    let blob = match find_blob(&db, id.as_str()).await? {
+        Some(blob) if !thumbnail || blob.has_thumbnail => blob,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    if let Some(user) = user {
+        if !can_access(&db, &blob, &user).await? {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    }
+
+    let (path, mime_type) = match thumbnail {
+        true => (thumbnail_path(&blob.id.unwrap()), "image/png".to_owned()),
+        false => (blob_path(&blob.id.unwrap()), blob.mime_type.clone()),
+    };
+    let content = tokio::fs::read(path).await?;
+
+    // Only images are rendered inline, anything else is downloaded to keep it from executing in our origin
+    let disposition = match is_image(mime_type.as_str()) {
+        true => DispositionType::Inline,
+        false => DispositionType::Attachment,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(mime_type)
+        .insert_header(content_disposition(disposition, blob.file_name.as_str()))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(content))
+}
+
+// Header values are ASCII only, `filename*` carries the real name and `filename` a fallback for old clients
+fn content_disposition(disposition: DispositionType, file_name: &str) -> ContentDisposition {
+    let fallback = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    ContentDisposition {
+        disposition,
+        parameters: vec![
+            DispositionParam::Filename(fallback),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_owned()),
+                language_tag: None,
+                value: file_name.as_bytes().to_vec(),
+            }),
+        ],
+    }
+}
+
+#[get("/blobs/{id}")]
+pub async fn download_blob(
+    db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
+    req: HttpRequest,
+    query: web::Query<BlobQuery>,
+    id: web::Path<String>,
+) -> Result<HttpResponse> {
+    serve_blob(db, pubsub, req, query, id.into_inner(), false).await
+}
+
+#[get("/blobs/{id}/thumbnail")]
+pub async fn download_thumbnail(
+    db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
+    req: HttpRequest,
+    query: web::Query<BlobQuery>,
+    id: web::Path<String>,
+) -> Result<HttpResponse> {
+    serve_blob(db, pubsub, req, query, id.into_inner(), true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_non_ascii_names_in_the_extended_parameter() {
+        let header = content_disposition(DispositionType::Attachment, "Übersicht \"Q1\".pdf");
+        assert_eq!(header.get_filename(), Some("_bersicht _Q1_.pdf"));
+        assert_eq!(header.get_filename_ext().unwrap().value, "Übersicht \"Q1\".pdf".as_bytes());
+        assert!(header.to_string().is_ascii());
+    }
+
+    #[test]
+    fn keeps_only_the_last_path_segment() {
+        assert_eq!(sanitize_file_name(Some("../../etc/passwd".to_owned())), "passwd");
+        assert_eq!(sanitize_file_name(Some("C:\\Users\\me\\report.pdf".to_owned())), "report.pdf");
+        assert_eq!(sanitize_file_name(Some("photo.png".to_owned())), "photo.png");
+    }
+
+    #[test]
+    fn strips_quotes_and_control_characters() {
+        assert_eq!(sanitize_file_name(Some("a\"b\r\n.txt".to_owned())), "ab.txt");
+    }
+
+    #[test]
+    fn falls_back_for_empty_names() {
+        assert_eq!(sanitize_file_name(None), "file");
+        assert_eq!(sanitize_file_name(Some("   ".to_owned())), "file");
+        assert_eq!(sanitize_file_name(Some("folder/".to_owned())), "file");
+    }
+
+    #[test]
+    fn limits_the_length() {
+        let name = sanitize_file_name(Some("x".repeat(300)));
+        assert_eq!(name.chars().count(), 255);
+    }
+
+    #[test]
+    fn signatures_are_bound_to_one_blob() {
+        let (blob, other) = (ObjectId::new(), ObjectId::new());
+        let url = signed_blob_url(&blob, false);
+        let signature = url.split_once("?signature=").unwrap().1;
+
+        assert!(verify_blob_signature(&blob, signature));
+        assert!(!verify_blob_signature(&other, signature));
+        assert!(!verify_blob_signature(&blob, "bl1.forged.signature"));
+    }
+}
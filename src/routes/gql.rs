@@ -12,11 +12,12 @@ use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::auth::signed::is_signed_token;
+use crate::auth::{authenticate, record_access, CurrentSession, RequestInfo};
+use crate::connections::PubSub;
 use crate::models::user::UserEntity;
+use crate::ModelFor;
 use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
-use chrono::Utc;
-use mongodb::bson::doc;
-use mongodb::options::FindOneOptions;
 use mongodb::Database;
 
 #[derive(Debug)]
@@ -31,15 +32,15 @@ fn query_guard(req: &GuardContext) -> bool {
     false
 }
 
-async fn get_user_from_token(db: Arc<Database>, auth_token: String) -> Option<UserEntity> {
-    let user_collection = db.collection::<UserEntity>("users");
-    let options = FindOneOptions::builder()
-        .projection(doc! { "password_hash": 0 })
-        .build();
-    if let Ok(Some(entity)) = user_collection.find_one(doc! { "access_token.token": auth_token, "access_token.expire": { "$gte": mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis()) } }, options).await {
-        return Some(entity);
+// Resolve user and session of a token and record the access
+pub async fn get_session_from_token(db: Arc<Database>, pubsub: &PubSub, auth_token: String) -> Option<(UserEntity, CurrentSession)> {
+    let users = ModelFor::<UserEntity>::new(db, "users");
+    let (user, session) = authenticate(&users, pubsub, auth_token.as_str()).await?;
+    // Signed tokens are meant to keep requests free of database writes
+    if !is_signed_token(auth_token.as_str()) {
+        record_access(&users, pubsub, &session).await;
     }
-    None
+    Some((user, session))
 }
 
 #[get("/graphql")]
@@ -53,18 +54,21 @@ pub async fn graphql_playground() -> Result<HttpResponse> {
 
 pub async fn on_connection_init(
     value: serde_json::Value,
-    db: Arc<Database>
+    db: Arc<Database>,
+    pubsub: PubSub,
+    info: RequestInfo,
 ) -> async_graphql::Result<Data> {
     #[derive(Debug, Deserialize)]
     struct Payload {
         authorization: String,
     }
     let mut data = Data::default();
+    data.insert(info);
 
     if let Ok(payload) = serde_json::from_value::<Payload>(value) {
-        if let Some(user) = get_user_from_token(db.clone(), payload.authorization).await {
-            db.collection::<UserEntity>("users").update_one(doc! { "_id": user.id.unwrap() }, doc! { "$set": { "last_access": mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis() )}}, None).await.expect("Error updating timestamp");
+        if let Some((user, session)) = get_session_from_token(db.clone(), &pubsub, payload.authorization).await {
             data.insert(user);
+            data.insert(session);
             return Ok(data);
         }
     }
@@ -77,15 +81,18 @@ pub async fn graphql_subscription(
     req: HttpRequest,
     payload: web::Payload,
     db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
 ) -> Result<HttpResponse> {
     let db = db.into_inner().clone();
+    let pubsub = PubSub::clone(&pubsub);
+    let info = RequestInfo::from_request(&req);
 
     GraphQLSubscription::new(Schema::clone(&*schema))
-        .on_connection_init(|value| on_connection_init(value, db))
+        .on_connection_init(|value| on_connection_init(value, db, pubsub, info))
         .start(&req, payload)
 }
 
-fn get_auth_from_headers(headers: &HeaderMap) -> Option<String> {
+pub fn get_auth_from_headers(headers: &HeaderMap) -> Option<String> {
     headers
         .get(header::AUTHORIZATION)
         .and_then(|value| value.to_str().map(|s| s.to_string()).ok())
@@ -95,17 +102,15 @@ fn get_auth_from_headers(headers: &HeaderMap) -> Option<String> {
 pub async fn graphql_request(
     schema: web::Data<GraphqlSchema>,
     db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
     req: HttpRequest,
     gql_request: GraphQLRequest,
 ) -> GraphQLResponse {
-    let mut request = gql_request.into_inner();
+    let mut request = gql_request.into_inner().data(RequestInfo::from_request(&req));
 
     if let Some(auth_token) = get_auth_from_headers(req.headers()) {
-        //TODO: Make the user being cached in redis
-        let db = db.into_inner();
-        if let Some(entity) = get_user_from_token(db.clone(), auth_token).await {
-            db.collection::<UserEntity>("users").update_one(doc! { "_id": entity.id.unwrap() }, doc! { "$set": { "last_access": mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis() )}}, None).await.expect("Error updating timestamp");
-            request = request.data(entity);
+        if let Some((entity, session)) = get_session_from_token(db.into_inner(), &pubsub, auth_token).await {
+            request = request.data(entity).data(session);
         }
     }
     schema.execute(request).await.into()
@@ -115,19 +120,18 @@ pub async fn graphql_request(
 pub async fn graphql_query(
     schema: web::Data<GraphqlSchema>,
     db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
     req: HttpRequest,
     gql_request: GraphQLRequest,
 ) -> GraphQLResponse {
-    let mut request = gql_request.into_inner();
+    let mut request = gql_request.into_inner().data(RequestInfo::from_request(&req));
 
     if let Some(query) = req.head().uri.query() {
         let query = Query::<HashMap<String, String>>::from_query(query).unwrap();
         if query.contains_key("authorization") {
             let auth_token = query.get("authorization").unwrap();
-            let db = db.into_inner();
-            if let Some(entity) = get_user_from_token(db.clone(), auth_token.clone()).await {
-                db.collection::<UserEntity>("users").update_one(doc! { "_id": entity.id.unwrap() }, doc! { "$set": { "last_access": mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis() )}}, None).await.expect("Error updating timestamp");
-                request = request.data(entity);
+            if let Some((entity, session)) = get_session_from_token(db.into_inner(), &pubsub, auth_token.clone()).await {
+                request = request.data(entity).data(session);
             }
         }
     }
@@ -1,2 +1,4 @@
+pub mod blob;
 pub mod gql;
 pub mod health;
+pub mod oidc;
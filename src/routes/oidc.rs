@@ -0,0 +1,114 @@
+use crate::auth::oidc::{authorization_url, complete_login, OIDC_BINDING_COOKIE, OIDC_CONFIG, OIDC_STATE_TTL};
+use crate::auth::RequestInfo;
+use crate::connections::PubSub;
+use crate::models::user::UserEntity;
+use crate::ModelFor;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use mongodb::Database;
+use serde::Deserialize;
+use url::form_urlencoded;
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+fn redirect(location: String) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish()
+}
+
+// Hand the result to the frontend in the fragment, it is neither sent to servers nor logged
+fn redirect_to_frontend(login_redirect: &str, params: &[(&str, String)]) -> HttpResponse {
+    let mut fragment = form_urlencoded::Serializer::new(String::new());
+    for (key, value) in params {
+        fragment.append_pair(key, value);
+    }
+    redirect(format!("{}#{}", login_redirect, fragment.finish()))
+}
+
+// Only sent along to the callback, never readable by scripts
+fn binding_cookie(value: String) -> Cookie<'static> {
+    Cookie::build(OIDC_BINDING_COOKIE, value)
+        .path("/auth/oidc")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(OIDC_STATE_TTL))
+        .finish()
+}
+
+// Start a login at the identity provider
+#[get("/auth/oidc/login")]
+pub async fn oidc_login(pubsub: web::Data<PubSub>) -> Result<HttpResponse> {
+    if OIDC_CONFIG.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    match authorization_url(&pubsub).await {
+        Ok((url, binding)) => {
+            let mut response = redirect(url);
+            response.add_cookie(&binding_cookie(binding))?;
+            Ok(response)
+        }
+        Err(err) => {
+            log::warn!("Cannot start oidc login: {}", err);
+            Ok(HttpResponse::BadGateway().body(err.to_string()))
+        }
+    }
+}
+
+// The identity provider redirects back here with the authorization code
+#[get("/auth/oidc/callback")]
+pub async fn oidc_callback(
+    db: web::Data<Database>,
+    pubsub: web::Data<PubSub>,
+    req: HttpRequest,
+    query: web::Query<CallbackQuery>,
+) -> Result<HttpResponse> {
+    let config = match OIDC_CONFIG.as_ref() {
+        Some(config) => config,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let query = query.into_inner();
+
+    let (code, state) = match (query.code, query.state) {
+        (Some(code), Some(state)) if query.error.is_none() => (code, state),
+        _ => {
+            let error = query
+                .error_description
+                .or(query.error)
+                .unwrap_or_else(|| "The login was cancelled".to_owned());
+            return Ok(redirect_to_frontend(config.login_redirect.as_str(), &[("error", error)]));
+        }
+    };
+
+    let users = ModelFor::<UserEntity>::new(db.into_inner(), "users");
+    let info = RequestInfo::from_request(&req);
+    let binding = req.cookie(OIDC_BINDING_COOKIE).map(|c| c.value().to_owned()).unwrap_or_default();
+    let mut response = match complete_login(&users, &pubsub, &info, code.as_str(), state.as_str(), binding.as_str()).await {
+        Ok(tokens) => redirect_to_frontend(
+            config.login_redirect.as_str(),
+            &[
+                ("access_token", tokens.access_token),
+                ("expire", tokens.access_expire.timestamp_millis().to_string()),
+                ("refresh_token", tokens.refresh_token),
+                ("refresh_expire", tokens.refresh_expire.timestamp_millis().to_string()),
+            ],
+        ),
+        Err(err) => {
+            log::warn!("Cannot complete oidc login: {}", err);
+            redirect_to_frontend(config.login_redirect.as_str(), &[("error", err.to_string())])
+        }
+    };
+    // The binding is only good for one login
+    response.add_removal_cookie(&binding_cookie(String::new()))?;
+    Ok(response)
+}
@@ -0,0 +1,134 @@
+// Characters shown before the first match of a snippet
+const SNIPPET_CONTEXT: usize = 40;
+// Maximum characters of a snippet, without ellipses
+const SNIPPET_LENGTH: usize = 160;
+
+pub struct Snippet {
+    pub text: String,
+    // Character offset and length of every match inside `text`
+    pub highlights: Vec<(usize, usize)>,
+}
+
+// Lowercased words and quoted phrases of a search query, negated words are left out
+pub fn search_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = vec![];
+
+    for (index, part) in query.split('"').enumerate() {
+        let candidates = match index % 2 {
+            // Inside quotes
+            1 => vec![part.trim().to_lowercase()],
+            _ => part
+                .split_whitespace()
+                .filter(|w| !w.starts_with('-'))
+                .map(|w| w.to_lowercase())
+                .collect::<Vec<String>>(),
+        };
+        for term in candidates {
+            if !term.is_empty() && !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+    }
+
+    terms
+}
+
+// Cut the part of a text around the first match and mark every match inside it
+pub fn snippet(text: &str, terms: &[String]) -> Snippet {
+    let chars = text.chars().collect::<Vec<char>>();
+    let lower = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect::<Vec<char>>();
+
+    let mut matches: Vec<(usize, usize)> = vec![];
+    for term in terms {
+        let term = term.chars().collect::<Vec<char>>();
+        if term.is_empty() || term.len() > lower.len() {
+            continue;
+        }
+        for start in 0..=lower.len() - term.len() {
+            if lower[start..start + term.len()] == term[..] {
+                matches.push((start, term.len()));
+            }
+        }
+    }
+    matches.sort();
+
+    // Drop matches overlapping a previous one
+    let mut highlights: Vec<(usize, usize)> = vec![];
+    for (start, length) in matches {
+        if highlights.last().map_or(true, |(s, l)| start >= s + l) {
+            highlights.push((start, length));
+        }
+    }
+
+    let start = highlights
+        .first()
+        .map_or(0, |(s, _)| s.saturating_sub(SNIPPET_CONTEXT));
+    let end = (start + SNIPPET_LENGTH).min(chars.len());
+    let prefix = if start > 0 { "…" } else { "" };
+    let suffix = if end < chars.len() { "…" } else { "" };
+    let offset = prefix.chars().count();
+
+    Snippet {
+        text: format!("{}{}{}", prefix, chars[start..end].iter().collect::<String>(), suffix),
+        highlights: highlights
+            .into_iter()
+            .filter(|(s, _)| *s >= start && *s < end)
+            .map(|(s, l)| (s - start + offset, l.min(end - s)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_words_and_phrases() {
+        assert_eq!(search_terms("hello World"), terms(&["hello", "world"]));
+        assert_eq!(search_terms("\"exact phrase\" other -excluded"), terms(&["exact phrase", "other"]));
+        assert_eq!(search_terms("a A a"), terms(&["a"]));
+        assert!(search_terms("\"\"").is_empty());
+        assert!(search_terms("  ").is_empty());
+    }
+
+    #[test]
+    fn highlights_matches_case_insensitive() {
+        let s = snippet("Rust is FUN", &terms(&["fun"]));
+        assert_eq!(s.text, "Rust is FUN");
+        assert_eq!(s.highlights, vec![(8, 3)]);
+    }
+
+    #[test]
+    fn drops_overlapping_matches() {
+        let s = snippet("abc", &terms(&["ab", "b"]));
+        assert_eq!(s.highlights, vec![(0, 2)]);
+    }
+
+    #[test]
+    fn cuts_long_texts_around_the_first_match() {
+        let text = format!("{} needle {}", "a".repeat(100), "b".repeat(100));
+        let s = snippet(text.as_str(), &terms(&["needle"]));
+
+        assert!(s.text.starts_with('…'));
+        assert!(!s.text.ends_with('…'));
+        assert_eq!(s.highlights, vec![(41, 6)]);
+        let highlighted = s.text.chars().skip(41).take(6).collect::<String>();
+        assert_eq!(highlighted, "needle");
+    }
+
+    #[test]
+    fn texts_without_matches_start_at_the_beginning() {
+        let text = "x".repeat(300);
+        let s = snippet(text.as_str(), &terms(&["missing"]));
+        assert!(s.highlights.is_empty());
+        assert_eq!(s.text.chars().count(), SNIPPET_LENGTH + 1);
+        assert!(s.text.ends_with('…'));
+    }
+}